@@ -1,15 +1,247 @@
 // use color_eyre::eyre::Result;
 
-use clap::Parser;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use crate::constants::DEFAULT_NTFY_SERVER;
+use clap::error::ErrorKind;
+use clap::{ArgMatches, Args, CommandFactory, Parser, Subcommand};
+use toml::Value;
+
+use crate::command::{
+    default_shell, parse_env_file, parse_env_variable, Launcher, RunOptions, StdinSource,
+};
+use crate::config::{SecondaryContent, TailRule, TailSettings};
+use crate::constants::{
+    DEFAULT_BATCH_INTERVAL, DEFAULT_BATCH_LINES, DEFAULT_HEARTBEAT_GRACE, DEFAULT_KILL_AFTER,
+};
+use crate::format::MessageFormat;
+use crate::ntfy::PriorityLevel;
+use crate::state::NotifyMode;
+use crate::stdin::{PipeMode, PipeOptions};
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Inspect the configuration (`~/.config/ntfy-log/config.toml` and `/etc/ntfy-log/config.toml`)
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Deliver notifications that were queued because the server could not be reached
+    Flush,
+    /// Inspect the jobs that run with `--heartbeat`, or alert about the ones that did not check in on time
+    Heartbeat {
+        #[command(subcommand)]
+        action: HeartbeatAction,
+    },
+    /// Follow log files (like `tail -F`) and send a notification when a line matches a rule
+    Tail(TailArgs),
+    /// Decrypt messages read from stdin (as sent, or ntfy's JSON stream, e.g. `curl -sN ntfy.sh/<topic>/json`)
+    Decrypt {
+        /// Encrypted attachments (or messages) to decrypt to stdout, instead of reading stdin
+        files: Vec<PathBuf>,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct TailArgs {
+    /// Topic to send the matches to
+    pub topic: String,
+
+    /// Files to follow
+    #[arg(required = true)]
+    pub files: Vec<PathBuf>,
+
+    /// Notify about lines matching this regex (in addition to the `[[tail.rules]]` from the config)
+    #[arg(short = 'm', long = "match", value_name = "REGEX")]
+    pub patterns: Vec<String>,
+
+    /// Priority of the `--match` notifications
+    #[arg(long, value_enum, default_value_t = PriorityLevel::High)]
+    pub priority: PriorityLevel,
+
+    /// Tags (emoji shortcodes) of the `--match` notifications, comma separated
+    #[arg(long, value_delimiter = ',')]
+    pub tags: Vec<String>,
+
+    /// Lines of context before and after a match (default: `tail.before`/`tail.after` from the config, or 3)
+    #[arg(short = 'C', long, value_name = "LINES")]
+    pub context: Option<usize>,
+
+    /// At most one notification per rule per period, later matches are summarized (default: `1m`)
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub rate_limit: Option<Duration>,
+
+    /// Start at the beginning of the files, instead of only following new lines
+    #[arg(long)]
+    pub from_start: bool,
+}
+
+impl TailArgs {
+    /// `--match` rules first, then the ones from the config.
+    pub fn rules(
+        &self,
+        settings: &TailSettings,
+    ) -> Vec<TailRule> {
+        let mut rules: Vec<TailRule> = self
+            .patterns
+            .iter()
+            .map(|pattern| TailRule {
+                pattern: pattern.clone(),
+                name: None,
+                priority: self.priority,
+                tags: self.tags.clone(),
+            })
+            .collect();
+
+        rules.extend(settings.rules.iter().cloned());
+        rules
+    }
+
+    /// The `[tail]` settings with the command line options applied.
+    pub fn settings(
+        &self,
+        settings: &TailSettings,
+    ) -> TailSettings {
+        let mut settings = settings.clone();
+
+        if let Some(context) = self.context {
+            settings.before = context;
+            settings.after = context;
+        }
+
+        if let Some(rate_limit) = self.rate_limit {
+            settings.rate_limit = rate_limit;
+        }
+
+        settings
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum HeartbeatAction {
+    /// Send an alert to the topic of every job whose last successful run is older than its heartbeat
+    /// (run this from a separate timer)
+    Check {
+        /// Extra time a job gets before it's considered overdue
+        #[arg(long, value_parser = humantime::parse_duration, default_value = DEFAULT_HEARTBEAT_GRACE)]
+        grace: Duration,
+    },
+    /// Print all jobs with a heartbeat and their last check-in
+    List {
+        #[arg(long, value_parser = humantime::parse_duration, default_value = DEFAULT_HEARTBEAT_GRACE)]
+        grace: Duration,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigAction {
+    /// Print the merged configuration and where each value came from
+    Show,
+}
 
 /// Either provide a channel and a command to run (`ntfy-log some-channel some-command --with-options`)
 /// or pipe the result of a command into this tool (`some-command --with-options | ntfy-log some-channel`)
 #[derive(Parser, Debug)]
+#[command(subcommand_negates_reqs = true)]
 pub struct Cli {
-    #[arg(short, long, default_value_t = (DEFAULT_NTFY_SERVER).into())]
-    pub endpoint: String,
+    /// ntfy server to send to (default: ntfy.sh)
+    #[arg(short, long)]
+    pub endpoint: Option<String>,
+
+    /// Send to `server/topic` (or a topic on `--endpoint`, a group from `[groups]` or another service's endpoint)
+    /// instead of `<topic>` on `--endpoint`, can be repeated. `<topic>` still names the job (e.g. for `--notify`)
+    #[arg(long, value_name = "TARGET")]
+    pub to: Vec<String>,
+
+    /// Use the settings of `[profile.<name>]` from the config file(s)
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// Access token for protected ntfy servers (takes precedence over `--user`/`--password`)
+    #[arg(long, env = "NTFY_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// Username for protected ntfy servers
+    #[arg(long, env = "NTFY_USER")]
+    pub user: Option<String>,
+
+    /// Password for protected ntfy servers
+    #[arg(long, env = "NTFY_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+
+    /// Don't queue notifications that could not be delivered (see `ntfy-log flush`)
+    #[arg(long)]
+    pub no_spool: bool,
+
+    /// Message size (in bytes) above which stdout and stderr are sent as attachments
+    #[arg(long, value_name = "BYTES")]
+    pub attach_threshold: Option<usize>,
+
+    /// Number of lines of stdout and stderr to keep in the message when the output is attached
+    #[arg(long, value_name = "LINES")]
+    pub tail_lines: Option<usize>,
+
+    /// Gzip attachments
+    #[arg(long)]
+    pub gzip_attachments: bool,
+
+    /// Expected time between successful runs: `ntfy-log heartbeat check` alerts when the command did not succeed for longer
+    #[arg(long, value_parser = humantime::parse_duration, value_name = "INTERVAL")]
+    pub heartbeat: Option<Duration>,
+
+    /// Exit codes that count as success, comma separated (default: 0)
+    #[arg(long, value_delimiter = ',', value_name = "CODES")]
+    pub ok_exit_codes: Vec<i32>,
+
+    /// Fail when stdout matches this regex, even if the exit code is ok
+    #[arg(long, value_name = "REGEX")]
+    pub fail_if_stdout_matches: Option<String>,
+
+    /// Fail when stderr matches this regex, even if the exit code is ok
+    #[arg(long, value_name = "REGEX")]
+    pub fail_if_stderr_matches: Option<String>,
+
+    /// Report a warning when stdout matches this regex
+    #[arg(long, value_name = "REGEX")]
+    pub warn_if_stdout_matches: Option<String>,
+
+    /// Report a warning when stderr matches this regex
+    #[arg(long, value_name = "REGEX")]
+    pub warn_if_stderr_matches: Option<String>,
+
+    /// Report a warning when the command wrote anything to stderr
+    #[arg(long)]
+    pub warn_if_stderr_nonempty: bool,
+
+    /// Encrypt messages and attachments with the (base64 encoded, 256-bit) key in this file (see `ntfy-log decrypt`)
+    #[arg(long, value_name = "PATH")]
+    pub encryption_key_file: Option<PathBuf>,
+
+    /// Send the command and its output as they are, without replacing secrets (see `[redact]`)
+    #[arg(long)]
+    pub allow_unredacted: bool,
+
+    /// When to send a notification (remembers the last outcomes of the command, per topic)
+    #[arg(long, value_enum)]
+    pub notify: Option<NotifyMode>,
+
+    /// Don't send the title to `{topic}--success`/`{topic}--failure`/`{topic}--timeout`
+    #[arg(long)]
+    pub no_secondary: bool,
+
+    /// Topic for the secondary message, `{topic}` and `{suffix}` are replaced (default: `{topic}--{suffix}`)
+    #[arg(long, value_name = "PATTERN")]
+    pub secondary_topic: Option<String>,
+
+    /// What to send to the secondary topic
+    #[arg(long, value_enum)]
+    pub secondary_content: Option<SecondaryContent>,
+
+    /// ntfy server for the secondary message (default: `--endpoint`)
+    #[arg(long)]
+    pub secondary_endpoint: Option<String>,
 
     #[arg(short = 'V', long)]
     pub version: bool,
@@ -20,20 +252,456 @@ pub struct Cli {
     #[arg(long)]
     pub self_update: bool,
 
-    #[arg(short, long, required = false, default_value_t=String::from(""))]
-    pub title: String,
+    /// Title of the message (default: the command and its duration), may be a template like `--template`
+    #[arg(short, long)]
+    pub title: Option<String>,
+
+    /// Format of the message body
+    #[arg(long, value_enum)]
+    pub format: Option<MessageFormat>,
+
+    /// Jinja-style template for `--format template` (or `@path/to/template`), e.g. `{{ command }}: {{ status }}`
+    #[arg(long)]
+    pub template: Option<String>,
+
+    /// Priority of the message, instead of the one for the outcome (see `[priority]`)
+    #[arg(short, long, value_enum)]
+    pub priority: Option<PriorityLevel>,
+
+    /// Tags (emoji shortcodes) of the message, comma separated
+    #[arg(long, value_delimiter = ',')]
+    pub tags: Vec<String>,
+
+    /// Don't add a tag for the outcome (e.g. `white_check_mark` or `rotating_light`)
+    #[arg(long)]
+    pub no_auto_tags: bool,
+
+    /// Open this url when the notification is clicked
+    #[arg(long, value_name = "URL")]
+    pub click: Option<String>,
+
+    /// Url of the notification icon
+    #[arg(long, value_name = "URL")]
+    pub icon: Option<String>,
+
+    /// Add a button, e.g. `view, Open dashboard, https://example.com` (can be repeated)
+    #[arg(long = "action", value_name = "ACTION")]
+    pub actions: Vec<String>,
+
+    /// Deliver the message this long after the command finished (e.g. `30m`)
+    #[arg(long, value_parser = humantime::parse_duration, conflicts_with = "at")]
+    pub delay: Option<Duration>,
+
+    /// Deliver the message at this time, e.g. `10am`, `tomorrow, 8am` or a unix timestamp
+    #[arg(long, value_name = "TIME")]
+    pub at: Option<String>,
+
+    /// Also forward the message to this email address
+    #[arg(long)]
+    pub email: Option<String>,
+
+    /// Run the command directly instead of with a shell, so arguments are passed on exactly as they are
+    #[arg(long, conflicts_with = "shell")]
+    pub exec: bool,
+
+    /// Shell that runs the command (default: bash, or `$SHELL` and then `/bin/sh` where bash isn't installed)
+    #[arg(long, value_name = "PATH")]
+    pub shell: Option<PathBuf>,
+
+    /// Run the command in this directory
+    #[arg(long, value_name = "DIR")]
+    pub cwd: Option<PathBuf>,
+
+    /// Set an environment variable for the command (can be repeated, only the names end up in the notification)
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env_variable)]
+    pub env: Vec<(String, String)>,
+
+    /// Set the `KEY=VALUE` lines of this file as environment variables for the command (can be repeated)
+    #[arg(long = "env-file", value_name = "PATH")]
+    pub env_files: Vec<PathBuf>,
+
+    /// Start the command with an empty environment (apart from `--env`/`--env-file`)
+    #[arg(long)]
+    pub clear_env: bool,
+
+    /// What the command reads from stdin: `null`, `inherit` (the stdin of ntfy-log) or a file
+    #[arg(long, value_name = "null|inherit|FILE", default_value = "null")]
+    pub stdin: StdinSource,
+
+    /// Run the command in a pseudo-terminal, so it keeps its colors and progress bars (stderr is merged into stdout)
+    #[arg(long, conflicts_with_all = ["fail_if_stderr_matches", "warn_if_stderr_matches", "warn_if_stderr_nonempty"])]
+    pub pty: bool,
+
+    /// Stop the command after this duration (e.g. `30s`, `5m`, `1h 30m`)
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub timeout: Option<Duration>,
+
+    /// Send a message when the command starts
+    #[arg(long)]
+    pub notify_start: bool,
+
+    /// Send an update with the elapsed time and the last lines of output this often while the command runs
+    #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub progress_every: Option<Duration>,
+
+    /// Grace period between SIGTERM and SIGKILL when the timeout expires
+    #[arg(long, value_parser = humantime::parse_duration, default_value = DEFAULT_KILL_AFTER)]
+    pub kill_after: Duration,
+
+    /// How piped stdin is sent: everything at once, one message per line or in batches
+    #[arg(long, value_enum, default_value_t = PipeMode::All)]
+    pub pipe: PipeMode,
+
+    /// Maximum number of lines per message with `--pipe batch`
+    #[arg(long, default_value_t = DEFAULT_BATCH_LINES)]
+    pub batch_lines: usize,
+
+    /// Maximum time before an incomplete batch is sent with `--pipe batch`
+    #[arg(long, value_parser = humantime::parse_duration, default_value = DEFAULT_BATCH_INTERVAL)]
+    pub batch_interval: Duration,
 
     #[arg(required = true, num_args(1), conflicts_with_all = ["self_update", "version"])]
     topic: Option<String>, // private, use get_topic instead!
 
     #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = false, num_args(0..))]
     pub subcommand: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
 }
 
 impl Cli {
+    /// Parse the arguments. If they don't fit the subcommand they start with (e.g. `ntfy-log config make`),
+    /// they're parsed as `<topic> <command>...` instead, so topics can still be named like a subcommand.
+    pub fn matches_from(
+        args: impl IntoIterator<Item = OsString>
+    ) -> Result<ArgMatches, clap::Error> {
+        let args: Vec<OsString> = args.into_iter().collect();
+
+        Self::command()
+            .try_get_matches_from(&args)
+            .or_else(|error| match error.kind() {
+                ErrorKind::DisplayHelp
+                | ErrorKind::DisplayVersion
+                | ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand => Err(error),
+                _ => Self::command_without_subcommands()
+                    .try_get_matches_from(&args)
+                    .map_err(|_| error),
+            })
+    }
+
+    /// The command with subcommands that can't be matched (no argument can contain a NUL byte).
+    fn command_without_subcommands() -> clap::Command {
+        let command = Self::command();
+        let names: Vec<String> = command
+            .get_subcommands()
+            .map(|subcommand| subcommand.get_name().to_string())
+            .collect();
+
+        names.iter().fold(command, |command, name| {
+            command.mut_subcommand(name, |subcommand| subcommand.name(format!("\0{name}")))
+        })
+    }
+
     pub fn get_topic(&self) -> &str {
         self.topic
             .as_ref()
             .expect("topic is marked as `required = true` so we can assume it's there.")
     }
+
+    /// `--priority`, `--tags`, `--action`, `--delay` and `--at`, as config overrides.
+    fn message_overrides(&self) -> Vec<(&'static str, &'static str, Value)> {
+        let mut overrides = Vec::new();
+
+        if let Some(delay) = self.delay {
+            let delay = humantime::format_duration(delay).to_string();
+            overrides.push(("delay", "delay", Value::String(delay)));
+        }
+
+        if let Some(at) = &self.at {
+            overrides.push(("at", "delay", Value::String(at.clone())));
+        }
+
+        let lists = [
+            ("tags", "tags", &self.tags),
+            ("actions", "actions", &self.actions),
+        ];
+
+        overrides.extend(
+            lists
+                .into_iter()
+                .filter(|(_, _, values)| !values.is_empty())
+                .map(|(id, key, values)| {
+                    let values = values.iter().cloned().map(Value::String).collect();
+                    (id, key, Value::Array(values))
+                }),
+        );
+
+        if let Some(priority) = self.priority {
+            let priority = Value::try_from(priority).unwrap();
+            for key in [
+                "priority.success",
+                "priority.warning",
+                "priority.failure",
+                "priority.timeout",
+            ] {
+                overrides.push(("priority", key, priority.clone()));
+            }
+        }
+
+        overrides
+    }
+
+    /// `--ok-exit-codes` and the `--fail-if-*`/`--warn-if-*` rules, as config overrides.
+    fn outcome_overrides(&self) -> Vec<(&'static str, &'static str, Value)> {
+        let patterns = [
+            (
+                "fail_if_stdout_matches",
+                "outcome.fail_if_stdout_matches",
+                &self.fail_if_stdout_matches,
+            ),
+            (
+                "fail_if_stderr_matches",
+                "outcome.fail_if_stderr_matches",
+                &self.fail_if_stderr_matches,
+            ),
+            (
+                "warn_if_stdout_matches",
+                "outcome.warn_if_stdout_matches",
+                &self.warn_if_stdout_matches,
+            ),
+            (
+                "warn_if_stderr_matches",
+                "outcome.warn_if_stderr_matches",
+                &self.warn_if_stderr_matches,
+            ),
+        ];
+
+        let mut overrides: Vec<(&'static str, &'static str, Value)> = patterns
+            .into_iter()
+            .filter_map(|(id, key, value)| Some((id, key, Value::String(value.clone()?))))
+            .collect();
+
+        if !self.ok_exit_codes.is_empty() {
+            let codes = self
+                .ok_exit_codes
+                .iter()
+                .map(|&code| Value::Integer(i64::from(code)))
+                .collect();
+            overrides.push((
+                "ok_exit_codes",
+                "outcome.ok_exit_codes",
+                Value::Array(codes),
+            ));
+        }
+
+        if self.warn_if_stderr_nonempty {
+            overrides.push((
+                "warn_if_stderr_nonempty",
+                "outcome.warn_if_stderr_nonempty",
+                Value::Boolean(true),
+            ));
+        }
+
+        overrides
+    }
+
+    /// Options that override the config file, as (arg id, config key, value).
+    pub fn config_overrides(&self) -> Vec<(&'static str, &'static str, Value)> {
+        let strings = [
+            ("endpoint", &self.endpoint),
+            ("token", &self.token),
+            ("user", &self.user),
+            ("password", &self.password),
+            ("title", &self.title),
+            ("template", &self.template),
+            ("click", &self.click),
+            ("icon", &self.icon),
+            ("email", &self.email),
+        ];
+
+        let nested_strings = [
+            ("secondary_topic", "secondary.topic", &self.secondary_topic),
+            (
+                "secondary_endpoint",
+                "secondary.endpoint",
+                &self.secondary_endpoint,
+            ),
+        ];
+
+        let mut overrides: Vec<(&'static str, &'static str, Value)> = strings
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, key, Value::String(value.clone()?))))
+            .collect();
+
+        overrides.extend(
+            nested_strings
+                .into_iter()
+                .filter_map(|(id, key, value)| Some((id, key, Value::String(value.clone()?)))),
+        );
+
+        if let Some(path) = &self.encryption_key_file {
+            let path = path.to_string_lossy().into_owned();
+            overrides.push((
+                "encryption_key_file",
+                "encryption.key_file",
+                Value::String(path),
+            ));
+        }
+
+        if let Some(format) = self.format {
+            overrides.push(("format", "format", Value::try_from(format).unwrap()));
+        }
+
+        overrides.extend(self.outcome_overrides());
+        overrides.extend(self.message_overrides());
+
+        if let Some(mode) = self.notify {
+            overrides.push(("notify", "notify.mode", Value::try_from(mode).unwrap()));
+        }
+
+        if let Some(content) = self.secondary_content {
+            overrides.push((
+                "secondary_content",
+                "secondary.content",
+                Value::try_from(content).unwrap(),
+            ));
+        }
+
+        let integers = [
+            (
+                "attach_threshold",
+                "attachments.threshold",
+                self.attach_threshold,
+            ),
+            ("tail_lines", "attachments.tail_lines", self.tail_lines),
+        ];
+
+        overrides.extend(integers.into_iter().filter_map(|(id, key, value)| {
+            Some((id, key, Value::Integer(i64::try_from(value?).ok()?)))
+        }));
+
+        let flags = [
+            ("no_spool", "spool.enabled", self.no_spool, false),
+            (
+                "gzip_attachments",
+                "attachments.gzip",
+                self.gzip_attachments,
+                true,
+            ),
+            (
+                "no_secondary",
+                "secondary.enabled",
+                self.no_secondary,
+                false,
+            ),
+            (
+                "no_auto_tags",
+                "auto_tags.enabled",
+                self.no_auto_tags,
+                false,
+            ),
+            (
+                "allow_unredacted",
+                "redact.enabled",
+                self.allow_unredacted,
+                false,
+            ),
+        ];
+
+        overrides.extend(
+            flags
+                .into_iter()
+                .filter(|(_, _, set, _)| *set)
+                .map(|(id, key, _, value)| (id, key, Value::Boolean(value))),
+        );
+
+        overrides
+    }
+
+    /// Fails if an env file, the working directory or the stdin file is invalid.
+    pub fn run_options(&self) -> Result<RunOptions, String> {
+        let launcher = if self.exec {
+            Launcher::Exec
+        } else {
+            Launcher::Shell(self.shell.clone().unwrap_or_else(default_shell))
+        };
+
+        // files first, so `--env` overrides them
+        let mut env = Vec::new();
+        for path in &self.env_files {
+            let contents = fs::read_to_string(path)
+                .map_err(|error| format!("Could not read {}: {error}", path.display()))?;
+            let variables = parse_env_file(&contents)
+                .map_err(|error| format!("Invalid env file {}, {error}", path.display()))?;
+            env.extend(variables);
+        }
+        env.extend(self.env.iter().cloned());
+
+        if let Some(cwd) = &self.cwd {
+            if !cwd.is_dir() {
+                return Err(format!("--cwd {}: not a directory", cwd.display()));
+            }
+        }
+
+        let stdin = self.stdin.open().map_err(|error| match &self.stdin {
+            StdinSource::File(path) => format!("--stdin {}: {error}", path.display()),
+            _ => error.to_string(),
+        })?;
+
+        Ok(RunOptions {
+            launcher,
+            cwd: self.cwd.clone(),
+            env,
+            clear_env: self.clear_env,
+            stdin,
+            pty: self.pty,
+            timeout: self.timeout,
+            kill_after: self.kill_after,
+            progress_every: self.progress_every,
+        })
+    }
+
+    pub const fn pipe_options(&self) -> PipeOptions {
+        PipeOptions {
+            mode: self.pipe,
+            batch_lines: self.batch_lines,
+            batch_interval: self.batch_interval,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::FromArgMatches;
+
+    use super::{Cli, Commands};
+
+    fn parse(args: &str) -> Cli {
+        let args = std::iter::once("ntfy-log")
+            .chain(args.split_whitespace())
+            .map(Into::into);
+        Cli::from_arg_matches(&Cli::matches_from(args).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_topic_named_like_a_subcommand() {
+        assert!(matches!(
+            parse("config show").command,
+            Some(Commands::Config { .. })
+        ));
+        assert!(matches!(
+            parse("tail jobs app.log").command,
+            Some(Commands::Tail(_))
+        ));
+
+        for args in ["config echo hi", "flush echo hi", "-- tail echo hi"] {
+            let cli = parse(args);
+            assert!(cli.command.is_none(), "{args}");
+            assert_eq!(cli.subcommand, ["echo", "hi"]);
+        }
+        assert_eq!(parse("-- heartbeat make").get_topic(), "heartbeat");
+
+        assert!(Cli::matches_from(["ntfy-log".into(), "--bogus".into(), "config".into()]).is_err());
+    }
 }
@@ -1,11 +1,35 @@
-use atty::Stream;
-use ntfy::{Payload, Priority};
+use crate::ntfy::{Attachment, Payload, Priority};
+use chrono::{DateTime, Local, SecondsFormat};
 use owo_colors::OwoColorize;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::process::Command;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::{Child, ChildStderr, Command};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
 
+use crate::config::{AttachmentSettings, SecondaryContent, Settings};
+use crate::constants::MAX_ACTIONS;
+use crate::format::Formatter;
+use crate::helpers::{format_duration, shell_quote, tail};
 use crate::log::GlobalLogger;
+use crate::notifier::Details;
+use crate::outcome::Outcome;
+use crate::pty::{self, clean_output};
+use crate::redact::Redactor;
+use crate::signal::{exit_code_signal, signal_exit_code, signal_name};
+use crate::usage::ResourceUsage;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CommandResult {
@@ -14,100 +38,1355 @@ pub struct CommandResult {
     pub stdout: String,
     pub stderr: String,
     pub exit_code: i32,
+
+    #[serde(default)]
+    pub timed_out: bool,
+
+    /// Decided by the exit code and the `[outcome]` rules.
+    #[serde(default)]
+    pub outcome: Outcome,
+    /// Why the outcome differs from what the exit code says, e.g. "stdout matched `ERROR`".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome_reason: Option<String>,
+
+    /// Set when the command was terminated by a signal (`exit_code` is then 128 + signal).
+    #[serde(default)]
+    pub signal: Option<i32>,
+    #[serde(default)]
+    pub signal_name: Option<String>,
+    #[serde(default)]
+    pub core_dumped: bool,
+    /// Whether `signal` is only derived from the exit code a shell reported (`128 + signal`), the command itself
+    /// may also have exited with that code.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub signal_inferred: bool,
+
+    /// RFC3339 timestamps of when the command was started and when it finished.
+    pub started_at: String,
+    pub finished_at: String,
+    /// Wall-clock duration in milliseconds.
+    pub duration_ms: u64,
+
+    /// Not available when reading from stdin.
+    pub resource_usage: Option<ResourceUsage>,
+
+    /// Working directory of the command (not known when reading from stdin).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Names (not values) of the environment variables set with `--env`/`--env-file`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>,
+    /// Whether the command started without the environment of `ntfy-log` (`--clear-env`).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub env_cleared: bool,
+    /// Whether the command ran in a pseudo-terminal (`--pty`), `stdout` then also contains stderr.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pty: bool,
+    /// Of the `--notify-start`/`--progress-every` messages, which the notification replaces.
+    #[serde(skip)]
+    pub sequence_id: Option<String>,
 }
 
 impl CommandResult {
+    /// Non-preferred way, since `command`, `stderr` and `exit_code` are all missing!
+    pub fn from_stdin(input: String) -> Self {
+        let now = timestamp(Local::now());
+
+        Self {
+            command: String::from("<stdin>"), // command is not known when getting data from stdin
+            stdout: input,
+
+            stderr: String::new(), // stderr is usually not piped, unless it is combined with stdout into stdin.
+            exit_code: 0, // unfortunately, you can't get the exit code of a piped command ($PIPESTATUS is bash-only)
+            timed_out: false,
+            outcome: Outcome::Success,
+            outcome_reason: None,
+            signal: None,
+            signal_name: None,
+            core_dumped: false,
+            signal_inferred: false,
+            started_at: now.clone(),
+            finished_at: now,
+            duration_ms: 0,
+            resource_usage: None,
+            cwd: None,
+            env: Vec::new(),
+            env_cleared: false,
+            pty: false,
+            sequence_id: None,
+        }
+    }
+
+    pub const fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+
+    /// A warning still counts as a success (e.g. for `--notify on-failure` and heartbeats).
     pub const fn success(&self) -> bool {
-        self.exit_code == 0
+        matches!(self.outcome, Outcome::Success | Outcome::Warning)
     }
 
-    pub fn build_payload(
+    /// Description of how the command was terminated by a signal, e.g. `killed by SIGKILL (core dumped)`,
+    /// or `exited with 130 (likely killed by SIGINT)` if the signal is only derived from the exit code.
+    pub fn termination(&self) -> Option<String> {
+        let signal = self.signal?;
+        let name = self
+            .signal_name
+            .clone()
+            .unwrap_or_else(|| format!("signal {signal}"));
+
+        if self.signal_inferred {
+            return Some(format!(
+                "exited with {} (likely killed by {name})",
+                self.exit_code
+            ));
+        }
+
+        let core_dumped = if self.core_dumped {
+            " (core dumped)"
+        } else {
+            ""
+        };
+
+        Some(format!("killed by {name}{core_dumped}"))
+    }
+
+    /// Whether the output is too large for the message body (and should be attached instead).
+    pub const fn exceeds_threshold(
         &self,
-        topic: &str,
-    ) -> Payload {
-        let priority = if self.success() {
-            Priority::Default
+        settings: &AttachmentSettings,
+    ) -> bool {
+        settings.enabled && self.stdout.len() + self.stderr.len() > settings.threshold
+    }
+
+    /// stdout and stderr as (gzipped) attachments, if they are too large for the message body.
+    pub fn attachments(
+        &self,
+        settings: &AttachmentSettings,
+    ) -> Vec<Attachment> {
+        if !self.exceeds_threshold(settings) {
+            return Vec::new();
+        }
+
+        [("stdout.log", &self.stdout), ("stderr.log", &self.stderr)]
+            .into_iter()
+            .filter(|(_, output)| !output.is_empty())
+            .map(|(filename, output)| Attachment::from_text(filename, output, settings.gzip))
+            .collect()
+    }
+
+    /// e.g. `succeeded`, `failed with exit code 3`, `failed (stdout matched `ERROR`)`, `killed by SIGKILL` or `timed out`.
+    pub fn status_text(&self) -> String {
+        if let Some(termination) = self.termination().filter(|_| !self.timed_out) {
+            return termination;
+        }
+
+        match (self.outcome, &self.outcome_reason) {
+            (Outcome::Timeout, _) => String::from("timed out"),
+            (Outcome::Success, _) if self.exit_code == 0 => String::from("succeeded"),
+            (Outcome::Success, _) => format!("succeeded with exit code {}", self.exit_code),
+            (Outcome::Warning, Some(reason)) => format!("succeeded with a warning ({reason})"),
+            (Outcome::Warning, None) => String::from("succeeded with a warning"),
+            (Outcome::Failure, Some(reason)) => format!("failed ({reason})"),
+            (Outcome::Failure, None) => format!("failed with exit code {}", self.exit_code),
+        }
+    }
+
+    /// The command with its duration (and how it was terminated), e.g. `backup.sh (12m 03s)`.
+    pub fn default_title(&self) -> String {
+        let title = if self.timed_out {
+            format!("Timed out: {}", self.command)
         } else {
-            Priority::High
+            self.command.clone()
         };
 
-        let msg = serde_json::to_string(self).unwrap_or_else(|error| {
+        if self.resource_usage.is_none() {
+            // only for commands that were actually executed (not stdin)
+            return title;
+        }
+
+        let duration = format_duration(self.duration());
+
+        self.termination().map_or_else(
+            || format!("{title} ({duration})"),
+            |termination| format!("{title} ({duration}, {termination})"),
+        )
+    }
+
+    /// The full result as JSON, or a summary with only the last lines of stdout/stderr if it's too large.
+    pub fn json_message(
+        &self,
+        settings: &AttachmentSettings,
+    ) -> String {
+        self.try_json_message(settings).unwrap_or_else(|error| {
             let fallback = json!({
                 "error": error.to_string(),
             });
 
             fallback.to_string()
-        });
+        })
+    }
 
-        Payload::new(topic)
-            .title(&self.command)
+    fn try_json_message(
+        &self,
+        settings: &AttachmentSettings,
+    ) -> Result<String, serde_json::Error> {
+        if !self.exceeds_threshold(settings) {
+            return serde_json::to_string(self);
+        }
+
+        // keep some room for the other fields
+        let max_bytes = settings.threshold / 3;
+
+        let mut summary = serde_json::to_value(self)?;
+        summary["stdout"] = tail(&self.stdout, settings.tail_lines, max_bytes).into();
+        summary["stderr"] = tail(&self.stderr, settings.tail_lines, max_bytes).into();
+        summary["truncated"] = true.into();
+        summary["stdout_bytes"] = self.stdout.len().into();
+        summary["stderr_bytes"] = self.stderr.len().into();
+
+        serde_json::to_string(&summary)
+    }
+
+    /// The result as fields, for the services that don't show the ntfy message (see `Details`).
+    pub fn details(
+        &self,
+        settings: &Settings,
+    ) -> Details {
+        Formatter::new(self, settings, "").details()
+    }
+
+    /// The result is already redacted, but `--title` could contain secrets as well: the title is redacted here,
+    /// before it ends up in the actions (see `Formatter::failure_actions`).
+    pub fn build_payload(
+        &self,
+        topic: &str,
+        settings: &Settings,
+        redactor: &Redactor,
+        title_prefix: Option<&str>,
+    ) -> Payload {
+        let priority = Priority::from(settings.priority.for_result(self));
+
+        let formatter = Formatter::new(self, settings, topic);
+        let (msg, markdown) = formatter.message();
+        let title = format!(
+            "{}{}",
+            title_prefix.unwrap_or_default(),
+            redactor.redact(&formatter.title())
+        );
+
+        let mut payload = Payload::new(topic)
+            .title(title)
             .message(msg)
             .priority(priority)
+            .markdown(markdown);
+
+        let tags: Vec<&String> = settings
+            .auto_tags
+            .for_result(self)
+            .iter()
+            .chain(&settings.tags)
+            .collect();
+        if !tags.is_empty() {
+            payload = payload.tags(tags);
+        }
+
+        // both were checked when the config was loaded
+        if let Ok(Some(click)) = settings.click_url() {
+            payload = payload.click(click);
+        }
+
+        let mut actions = if matches!(self.outcome, Outcome::Failure | Outcome::Timeout) {
+            formatter.failure_actions(payload.title.as_deref().unwrap_or_default())
+        } else {
+            Vec::new()
+        };
+        actions.extend(settings.parsed_actions().unwrap_or_default());
+
+        if actions.len() > MAX_ACTIONS {
+            GlobalLogger::warn(format!(
+                "ntfy allows at most {MAX_ACTIONS} actions, dropping {} of them",
+                actions.len() - MAX_ACTIONS
+            ));
+            actions.truncate(MAX_ACTIONS);
+        }
+
+        if !actions.is_empty() {
+            payload = payload.actions(actions);
+        }
+
+        // a duration stays relative until it's sent (which may be later, from the spool)
+        if let Some(delay) = &settings.delay {
+            payload = Payload {
+                delay: Some(delay.clone()),
+                ..payload
+            };
+        }
+
+        if let Some(email) = &settings.email {
+            payload = payload.email(email);
+        }
+
+        payload
+    }
+
+    /// The message for the secondary topic, based on the primary payload and `secondary.content`.
+    pub fn build_secondary_payload(
+        &self,
+        primary: &Payload,
+        topic: &str,
+        settings: &Settings,
+    ) -> Payload {
+        let secondary = &settings.secondary;
+        let title = primary.title.clone().unwrap_or_default();
+
+        match secondary.content {
+            SecondaryContent::Title => {
+                Payload::new(secondary.topic_for(topic, self)).message(title)
+            },
+            SecondaryContent::Summary => {
+                let summary = format!(
+                    "{} after {}",
+                    self.status_text(),
+                    format_duration(self.duration())
+                );
+
+                Payload {
+                    tags: primary.tags.clone(),
+                    click: primary.click.clone(),
+                    delay: primary.delay.clone(),
+                    ..Payload::new(secondary.topic_for(topic, self))
+                        .title(title)
+                        .message(summary)
+                        .priority(Priority::from(settings.priority.for_result(self)))
+                }
+            },
+            SecondaryContent::Full => {
+                let mut payload = primary.clone();
+                payload.topic = secondary.topic_for(topic, self);
+                payload
+            },
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct InvalidArgsNoStdIn {}
 
-/// Non-preferred way, since `command`, `stderr` and `exit_code` are all missing!
-pub fn try_stdin() -> Result<CommandResult, InvalidArgsNoStdIn> {
-    if atty::is(Stream::Stdin) {
-        return Err(InvalidArgsNoStdIn {});
-    }
+fn timestamp(time: DateTime<Local>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, false)
+}
 
-    GlobalLogger::important("warn".yellow().to_string(),
-                            "Since complex bash commands (including pipes) is now supported by ntfy-push, using stdin is highly discouraged.");
+/// Exit code used when the command was stopped because of `--timeout` (same as coreutils' `timeout`).
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
 
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input).unwrap();
+/// How the wrapped command is started.
+#[derive(Debug, Clone)]
+pub enum Launcher {
+    /// `<shell> -c "<args joined by spaces>"`, so the command can use pipes, `&&`, globs, ...
+    Shell(PathBuf),
+    /// The args as they are, without a shell (`--exec`).
+    Exec,
+}
 
-    Ok(CommandResult {
-        command: String::from("<stdin>"), // command is not known when getting data from stdin
-        stdout: input,
+impl Default for Launcher {
+    fn default() -> Self {
+        Self::Shell(default_shell())
+    }
+}
 
-        stderr: String::new(), // stderr is usually not piped, unless it is combined with stdout into stdin.
-        exit_code: 0, // unfortunately, you can't get the exit code of a piped command ($PIPESTATUS is bash-only)
+/// Whether `name` is an executable file in one of the `$PATH` directories.
+fn in_path(name: &str) -> bool {
+    std::env::var_os("PATH").is_some_and(|path| {
+        std::env::split_paths(&path).any(|dir| {
+            fs::metadata(dir.join(name)).is_ok_and(|metadata| {
+                metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+            })
+        })
     })
 }
 
-pub async fn run_cmd(args: &[String]) -> Result<CommandResult, InvalidArgsNoStdIn> {
+/// bash, or `$SHELL` (and then `/bin/sh`) on systems without it.
+pub fn default_shell() -> PathBuf {
+    if in_path("bash") {
+        return PathBuf::from("bash");
+    }
+
+    std::env::var_os("SHELL")
+        .filter(|shell| !shell.is_empty())
+        .map_or_else(|| PathBuf::from("/bin/sh"), PathBuf::from)
+}
+
+/// The command as it is recorded (and shown): what the shell runs, or the args quoted so the command can be
+/// reproduced in a shell (`--exec`).
+pub fn command_line(
+    args: &[String],
+    launcher: &Launcher,
+) -> String {
+    match launcher {
+        Launcher::Shell(_) => args.join(" "),
+        Launcher::Exec => args
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+/// Exit code for a command that could not be started, like a shell's (127 if it doesn't exist).
+fn spawn_error_exit_code(error: &std::io::Error) -> i32 {
+    match error.kind() {
+        ErrorKind::NotFound => 127,
+        ErrorKind::PermissionDenied => 126,
+        _ => error.raw_os_error().unwrap_or(-1),
+    }
+}
+
+/// What the wrapped command reads from stdin (`--stdin`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum StdinSource {
+    /// Nothing, like `< /dev/null`.
+    #[default]
+    Null,
+    /// The stdin of `ntfy-log`.
+    Inherit,
+    File(PathBuf),
+}
+
+impl FromStr for StdinSource {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "null" => Ok(Self::Null),
+            "inherit" => Ok(Self::Inherit),
+            "" => Err(String::from("expected `null`, `inherit` or a file")),
+            path => Ok(Self::File(PathBuf::from(path))),
+        }
+    }
+}
+
+/// A `StdinSource` with its file opened.
+#[derive(Debug, Default)]
+pub enum Stdin {
+    #[default]
+    Null,
+    Inherit,
+    File(File),
+}
+
+impl StdinSource {
+    pub fn open(&self) -> std::io::Result<Stdin> {
+        Ok(match self {
+            Self::Null => Stdin::Null,
+            Self::Inherit => Stdin::Inherit,
+            Self::File(path) => Stdin::File(File::open(path)?),
+        })
+    }
+}
+
+/// Parse the `KEY=VALUE` lines of an env file (`export KEY=VALUE` and quoted values work as well,
+/// empty lines and `#` comments are skipped).
+pub fn parse_env_file(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut variables = Vec::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) =
+            parse_env_variable(line).map_err(|error| format!("line {}: {error}", number + 1))?;
+
+        let value = value.trim();
+        let unquoted = ['"', '\'']
+            .iter()
+            .find_map(|&quote| value.strip_prefix(quote)?.strip_suffix(quote))
+            .unwrap_or(value);
+
+        variables.push((key, unquoted.to_string()));
+    }
+
+    Ok(variables)
+}
+
+/// `KEY=VALUE` (as in `--env`).
+pub fn parse_env_variable(variable: &str) -> Result<(String, String), String> {
+    match variable.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() && !key.contains(char::is_whitespace) => {
+            Ok((key.to_string(), value.to_string()))
+        },
+        _ => Err(format!("expected `KEY=VALUE`, got `{variable}`")),
+    }
+}
+
+/// Settings that control how the wrapped command is executed.
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    pub launcher: Launcher,
+    /// Working directory of the command (default: the current one).
+    pub cwd: Option<PathBuf>,
+    /// Set for the command, later ones override earlier ones.
+    pub env: Vec<(String, String)>,
+    /// Start the command with only `env`.
+    pub clear_env: bool,
+    pub stdin: Stdin,
+    /// Run the command in a pseudo-terminal, so it keeps its colors and progress bars.
+    pub pty: bool,
+    /// Stop the command after this duration.
+    pub timeout: Option<Duration>,
+    /// Time between SIGTERM and SIGKILL once the timeout expired.
+    pub kill_after: Duration,
+    /// Report `Progress` this often while the command runs.
+    pub progress_every: Option<Duration>,
+}
+
+/// Size of the buffer used to read chunks of the child's stdout/stderr.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Everything the child wrote so far, shared between the reading task and `run_cmd`.
+#[derive(Debug, Default)]
+struct Captured {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+type SharedCapture = Arc<Mutex<Captured>>;
+
+/// Snapshot of a running command, see `RunOptions::progress_every`.
+#[derive(Debug)]
+pub struct Progress {
+    pub elapsed: Duration,
+    /// All of stdout and stderr so far, so secrets can be redacted before it's cut down to the last lines.
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// The output as text, cleaned up if it was written to a pseudo-terminal.
+fn output_text(
+    output: &[u8],
+    pty: bool,
+) -> String {
+    let text = String::from_utf8_lossy(output);
+
+    if pty {
+        clean_output(&text)
+    } else {
+        text.into_owned()
+    }
+}
+
+/// Send a `Progress` every `every`, until the receiver is gone (or this task is aborted).
+async fn report_progress(
+    every: Duration,
+    capture: SharedCapture,
+    start: Instant,
+    sender: mpsc::Sender<Progress>,
+    pty: bool,
+) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let progress = {
+            let captured = capture.lock().unwrap();
+            Progress {
+                elapsed: start.elapsed(),
+                stdout: output_text(&captured.stdout, pty),
+                stderr: output_text(&captured.stderr, pty),
+            }
+        };
+
+        if sender.send(progress).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Read a chunk from a (still open) pipe. Closed or missing pipes never resolve.
+async fn read_chunk<R: AsyncRead + Unpin>(
+    pipe: &mut Option<R>,
+    buffer: &mut [u8],
+) -> std::io::Result<usize> {
+    match pipe {
+        Some(reader) => reader.read(buffer).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Which output of the command a chunk was written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Stdout,
+    Stderr,
+}
+
+/// Streamed output goes to the same output of ntfy-log (unless `-q`).
+fn forward_to_terminal(
+    output: Output,
+    chunk: &[u8],
+) {
+    match output {
+        Output::Stdout => GlobalLogger::write_stdout(chunk),
+        Output::Stderr => GlobalLogger::write_stderr(chunk),
+    }
+}
+
+/// Forward stdout and stderr as soon as data arrives (keeping their interleaving, see `forward_to_terminal`),
+/// while also capturing everything for the notification.
+async fn stream_output<O: AsyncRead + Unpin, E: AsyncRead + Unpin, F: FnMut(Output, &[u8])>(
+    mut stdout: Option<O>,
+    mut stderr: Option<E>,
+    capture: SharedCapture,
+    mut forward: F,
+) {
+    let mut stdout_buffer = vec![0; CHUNK_SIZE];
+    let mut stderr_buffer = vec![0; CHUNK_SIZE];
+
+    while stdout.is_some() || stderr.is_some() {
+        tokio::select! {
+            read = read_chunk(&mut stdout, &mut stdout_buffer) => match read {
+                Ok(0) | Err(_) => stdout = None,
+                Ok(size) => {
+                    let chunk = &stdout_buffer[..size];
+                    forward(Output::Stdout, chunk);
+                    capture.lock().unwrap().stdout.extend_from_slice(chunk);
+                },
+            },
+            read = read_chunk(&mut stderr, &mut stderr_buffer) => match read {
+                Ok(0) | Err(_) => stderr = None,
+                Ok(size) => {
+                    let chunk = &stderr_buffer[..size];
+                    forward(Output::Stderr, chunk);
+                    capture.lock().unwrap().stderr.extend_from_slice(chunk);
+                },
+            },
+        }
+    }
+}
+
+/// Whether the command gets its own process group, so `--timeout` also stops whatever it started.
+/// Otherwise it stays in the group of ntfy-log, which is what the terminal expects (e.g. for password prompts).
+const fn own_process_group(options: &RunOptions) -> bool {
+    options.pty || options.timeout.is_some()
+}
+
+/// Where signals for the command go: its whole process group. `None` if it shares the group of ntfy-log,
+/// then it gets the signals from the terminal (e.g. Ctrl+C) itself.
+fn signal_target(
+    child: &Child,
+    options: &RunOptions,
+) -> Option<libc::pid_t> {
+    let pid = child.id()? as libc::pid_t;
+    own_process_group(options).then_some(-pid)
+}
+
+/// A copy of the terminal (closed on exec) if ntfy-log runs in its foreground process group.
+fn foreground_terminal() -> Option<OwnedFd> {
+    [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO]
+        .into_iter()
+        // SAFETY: no memory is involved, `tcgetpgrp` fails for anything that isn't a terminal.
+        .find(|&fd| unsafe { libc::tcgetpgrp(fd) == libc::getpgrp() })
+        // SAFETY: the standard streams stay open while ntfy-log runs.
+        .and_then(|fd| unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned().ok())
+}
+
+/// Make `group` the foreground process group of `terminal`. SIGTTOU is blocked meanwhile, which would otherwise stop
+/// a caller in a background group. Only async-signal-safe calls, so the command can use it before exec as well.
+fn set_foreground(
+    terminal: RawFd,
+    group: libc::pid_t,
+) {
+    // SAFETY: the signal sets are initialized by `sigemptyset`/`pthread_sigmask` before they're read.
+    unsafe {
+        let mut blocked = std::mem::zeroed();
+        let mut previous = std::mem::zeroed();
+        libc::sigemptyset(&raw mut blocked);
+        libc::sigaddset(&raw mut blocked, libc::SIGTTOU);
+
+        libc::pthread_sigmask(libc::SIG_BLOCK, &raw const blocked, &raw mut previous);
+        libc::tcsetpgrp(terminal, group);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &raw const previous, std::ptr::null_mut());
+    }
+}
+
+/// While the command runs in a process group of its own, that group is in the foreground of the terminal
+/// (so it can read from it, and gets Ctrl+C). ntfy-log takes the terminal back when this is dropped.
+struct Foreground(OwnedFd);
+
+impl Foreground {
+    fn give(
+        terminal: OwnedFd,
+        group: libc::pid_t,
+    ) -> Self {
+        set_foreground(terminal.as_raw_fd(), group);
+        Self(terminal)
+    }
+}
+
+impl Drop for Foreground {
+    fn drop(&mut self) {
+        // SAFETY: `getpgrp` always succeeds.
+        set_foreground(self.0.as_raw_fd(), unsafe { libc::getpgrp() });
+    }
+}
+
+/// Send `signal` to the command (see `signal_target`).
+fn send_signal(
+    target: Option<libc::pid_t>,
+    signal: libc::c_int,
+) {
+    if let Some(target) = target {
+        // SAFETY: `kill` has no memory safety requirements, worst case the group no longer exists.
+        unsafe {
+            libc::kill(target, signal);
+        }
+    }
+}
+
+/// Wait for the child to exit. Signals that would stop ntfy-log are passed on if the command is in its own process
+/// group (see `signal_target`), ntfy-log keeps running either way to report how the command ended.
+async fn wait_forwarding(
+    child: &mut Child,
+    target: Option<libc::pid_t>,
+) -> std::io::Result<ExitStatus> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    loop {
+        tokio::select! {
+            status = child.wait() => return status,
+            _ = interrupt.recv() => send_signal(target, libc::SIGINT),
+            _ = terminate.recv() => send_signal(target, libc::SIGTERM),
+            _ = hangup.recv() => send_signal(target, libc::SIGHUP),
+        }
+    }
+}
+
+/// Wait for the child to exit. When the timeout expires, send SIGTERM and escalate to SIGKILL after the grace period
+/// (both to the whole process group, see `signal_target`).
+/// Returns the exit status and whether the command timed out.
+async fn wait_with_timeout(
+    child: &mut Child,
+    options: &RunOptions,
+) -> (std::io::Result<ExitStatus>, bool) {
+    let target = signal_target(child, options);
+
+    let Some(timeout) = options.timeout else {
+        return (wait_forwarding(child, target).await, false);
+    };
+
+    if let Ok(status) = tokio::time::timeout(timeout, wait_forwarding(child, target)).await {
+        return (status, false);
+    }
+
     let logger = GlobalLogger::singleton();
+    logger.warn(format!(
+        "Command timed out after {}, sending SIGTERM",
+        humantime::format_duration(timeout)
+    ));
+    send_signal(target, libc::SIGTERM);
+
+    let status = tokio::time::timeout(options.kill_after, wait_forwarding(child, target)).await;
+
+    if status.is_err() {
+        logger.warn(format!(
+            "Command still running {} after SIGTERM, sending SIGKILL",
+            humantime::format_duration(options.kill_after)
+        ));
+    }
 
-    if args.is_empty() {
-        // no subcommand arg(s), hopefully something was piped.
-        return try_stdin();
+    // also for anything the command started that outlived it
+    send_signal(target, libc::SIGKILL);
+
+    match status {
+        Ok(status) => (status, true),
+        Err(_) => (child.wait().await, true),
+    }
+}
+
+/// The process to start for `args` (`command` is what the shell runs), with its output piped
+/// (or written to a pseudo-terminal, of which the master is returned as well).
+/// In a process group of its own, the command takes over `terminal` (see `Foreground`).
+/// Fails if the pseudo-terminal can't be opened.
+fn build_command(
+    args: &[String],
+    command: &str,
+    options: &RunOptions,
+    terminal: Option<RawFd>,
+) -> std::io::Result<(Command, Option<pty::Master>)> {
+    let mut cmd = match &options.launcher {
+        Launcher::Shell(shell) => {
+            let mut cmd = std::process::Command::new(shell);
+            cmd.arg("-c");
+            cmd.arg(command);
+            // -> <shell> -c "<full command>"
+            cmd
+        },
+        Launcher::Exec => {
+            let mut cmd = std::process::Command::new(&args[0]);
+            cmd.args(&args[1..]);
+            cmd
+        },
+    };
+
+    if options.clear_env {
+        cmd.env_clear();
+    }
+    cmd.envs(options.env.iter().map(|(key, value)| (key, value)));
+
+    if let Some(cwd) = &options.cwd {
+        cmd.current_dir(cwd);
+    }
+
+    if options.pty {
+        // SAFETY: setsid and ioctl are async-signal-safe. Like in a terminal emulator, the command gets a session
+        // (and process group) of its own, with the pseudo-terminal on its stdout as the controlling terminal.
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() < 0 || libc::ioctl(libc::STDOUT_FILENO, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    } else if let (true, Some(terminal)) = (own_process_group(options), terminal) {
+        // SAFETY: setpgid is async-signal-safe, and so is `set_foreground`. ntfy-log does the same after spawning,
+        // whichever comes first keeps the command from being stopped when it uses the terminal right away.
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::setpgid(0, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                set_foreground(terminal, libc::getpgrp());
+                Ok(())
+            });
+        }
+    } else if own_process_group(options) {
+        cmd.process_group(0);
+    }
+
+    let stdin = match &options.stdin {
+        Stdin::Null => Stdio::null(),
+        Stdin::Inherit => Stdio::inherit(),
+        Stdin::File(file) => Stdio::from(file.try_clone()?),
+    };
+
+    cmd.stdin(stdin);
+
+    if !options.pty {
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        return Ok((Command::from(cmd), None));
+    }
+
+    let (master, terminal) = pty::open()?;
+    cmd.stdout(Stdio::from(terminal.try_clone()?));
+    cmd.stderr(Stdio::from(terminal));
+
+    // without TERM (e.g. in cron) most programs still assume they can't use colors
+    let has_term = options.env.iter().any(|(key, _)| key == "TERM")
+        || (!options.clear_env && std::env::var_os("TERM").is_some());
+    if !has_term {
+        cmd.env("TERM", "xterm-256color");
+    }
+
+    Ok((Command::from(cmd), Some(master)))
+}
+
+/// Start the command, in the foreground of the terminal if it gets a process group of its own.
+fn spawn_command(
+    args: &[String],
+    command: &str,
+    options: &RunOptions,
+) -> std::io::Result<(Child, Option<pty::Master>, Option<Foreground>)> {
+    let terminal = (own_process_group(options) && !options.pty)
+        .then(foreground_terminal)
+        .flatten();
+
+    // `cmd` is dropped right after spawning, so only the child keeps the pseudo-terminal open
+    let (mut cmd, master) = build_command(
+        args,
+        command,
+        options,
+        terminal.as_ref().map(AsRawFd::as_raw_fd),
+    )?;
+    let child = cmd.spawn().map_err(|error| {
+        let program = cmd.as_std().get_program().to_string_lossy().into_owned();
+        std::io::Error::new(error.kind(), format!("{program}: {error}"))
+    })?;
+
+    let foreground = terminal
+        .zip(child.id())
+        .map(|(terminal, pid)| Foreground::give(terminal, pid as libc::pid_t));
+
+    Ok((child, master, foreground))
+}
+
+/// The absolute working directory of the command.
+fn working_dir(options: &RunOptions) -> Option<String> {
+    let current = std::env::current_dir().ok();
+
+    let cwd = match (&options.cwd, current) {
+        (Some(cwd), Some(current)) => current.join(cwd),
+        (Some(cwd), None) => cwd.clone(),
+        (None, current) => current?,
+    };
+
+    Some(cwd.to_string_lossy().into_owned())
+}
+
+/// The names of the variables that are set for the command (each once).
+fn env_names(options: &RunOptions) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+
+    for (key, _) in &options.env {
+        if !names.contains(key) {
+            names.push(key.clone());
+        }
+    }
+
+    names
+}
+
+/// The result for a command that could not be started.
+fn spawn_failed(
+    command: String,
+    error: &std::io::Error,
+    started_at: DateTime<Local>,
+    options: &RunOptions,
+) -> CommandResult {
+    CommandResult {
+        command,
+        stdout: String::new(),
+        stderr: error.to_string(),
+        exit_code: spawn_error_exit_code(error),
+        timed_out: false,
+        outcome: Outcome::Failure,
+        outcome_reason: None,
+        signal: None,
+        signal_name: None,
+        core_dumped: false,
+        signal_inferred: false,
+        started_at: timestamp(started_at),
+        finished_at: timestamp(Local::now()),
+        duration_ms: 0,
+        resource_usage: None,
+        cwd: working_dir(options),
+        env: env_names(options),
+        env_cleared: options.clear_env,
+        pty: options.pty,
+        sequence_id: None,
+    }
+}
+
+/// The exit code, the signal that killed the command (if any), whether it dumped core and whether the signal is
+/// inferred. A `shell` reports a command it started that was killed with `128 + signal`, that's mapped back to the
+/// signal. Without a shell, such an exit code is just what the command exited with.
+fn exit_details(
+    status: &std::io::Result<ExitStatus>,
+    shell: bool,
+) -> (i32, Option<i32>, bool, bool) {
+    match status {
+        Ok(status) => match (status.code(), status.signal()) {
+            (Some(code), _) => {
+                let signal = exit_code_signal(code).filter(|_| shell);
+                (code, signal, false, signal.is_some())
+            },
+            (None, Some(signal)) => (
+                signal_exit_code(signal),
+                Some(signal),
+                status.core_dumped(),
+                false,
+            ),
+            (None, None) => (-1, None, false, false),
+        },
+        Err(error) => (error.raw_os_error().unwrap_or(-1), None, false, false),
     }
+}
+
+pub async fn run_cmd(
+    args: &[String],
+    options: &RunOptions,
+    progress: Option<mpsc::Sender<Progress>>,
+) -> Result<CommandResult, InvalidArgsNoStdIn> {
+    let logger = GlobalLogger::singleton();
 
-    let command = args.join(" ");
+    let command = command_line(args, &options.launcher);
     logger.info(command.blue().to_string());
 
     if command.is_empty() {
         return Err(InvalidArgsNoStdIn {});
     }
 
-    let mut cmd = Command::new("bash");
-    cmd.arg("-c");
-    cmd.arg(&command);
-    // -> bash -c "<full command>"
+    let usage_before = ResourceUsage::children();
+    let started_at = Local::now();
+    let start = Instant::now();
 
-    let result = match cmd.output().await {
-        Ok(output) => CommandResult {
-            command,
-            stdout: String::from_utf8(output.stdout).unwrap_or_default(),
-            stderr: String::from_utf8(output.stderr).unwrap_or_default(),
-            exit_code: output.status.code().unwrap_or(-1),
+    let (mut child, master, foreground) = match spawn_command(args, &command, options) {
+        Ok(spawned) => spawned,
+        Err(error) => {
+            let result = spawn_failed(command, &error, started_at, options);
+            logger.stderr(&result.stderr);
+            return Ok(result);
         },
+    };
 
-        Err(error) => CommandResult {
-            command,
-            stdout: String::new(),
-            stderr: error.to_string(),
-            exit_code: error.raw_os_error().unwrap_or(-1),
-        },
+    let resizing = master
+        .as_ref()
+        .and_then(|master| master.follow_window_size().ok());
+
+    // output is printed while streaming, so it's not logged again afterwards:
+    let capture = SharedCapture::default();
+    let mut streaming = match master {
+        // stdout and stderr are the same terminal
+        Some(master) => tokio::spawn(stream_output(
+            Some(master),
+            None::<ChildStderr>,
+            Arc::clone(&capture),
+            forward_to_terminal,
+        )),
+        None => tokio::spawn(stream_output(
+            child.stdout.take(),
+            child.stderr.take(),
+            Arc::clone(&capture),
+            forward_to_terminal,
+        )),
     };
 
-    logger.stdout(&result.stdout);
-    logger.stderr(&result.stderr);
+    let reporter = options.progress_every.zip(progress).map(|(every, sender)| {
+        tokio::spawn(report_progress(
+            every,
+            Arc::clone(&capture),
+            start,
+            sender,
+            options.pty,
+        ))
+    });
+
+    let (status, timed_out) = wait_with_timeout(&mut child, options).await;
+    drop(foreground);
+
+    for task in [reporter, resizing].into_iter().flatten() {
+        task.abort();
+    }
+
+    let duration = start.elapsed();
+    let finished_at = Local::now();
+    let resource_usage = ResourceUsage::children()
+        .zip(usage_before)
+        .map(|(after, before)| after.since(&before));
+
+    if timed_out {
+        // processes started by the shell could still hold on to the pipes, don't wait for them forever:
+        if tokio::time::timeout(options.kill_after, &mut streaming)
+            .await
+            .is_err()
+        {
+            streaming.abort();
+        }
+    } else {
+        streaming.await.unwrap_or_default();
+    }
+
+    let shell = matches!(options.launcher, Launcher::Shell(_));
+    let (exit_code, signal, core_dumped, signal_inferred) = exit_details(&status, shell);
+
+    let exit_code = if timed_out {
+        TIMEOUT_EXIT_CODE
+    } else {
+        exit_code
+    };
+
+    let captured = capture.lock().unwrap();
+
+    Ok(CommandResult {
+        command,
+        stdout: output_text(&captured.stdout, options.pty),
+        stderr: output_text(&captured.stderr, options.pty),
+        exit_code,
+        timed_out,
+        outcome: Outcome::from_exit_code(exit_code, timed_out),
+        outcome_reason: None,
+        signal,
+        signal_name: signal.and_then(signal_name).map(String::from),
+        core_dumped,
+        signal_inferred,
+        started_at: timestamp(started_at),
+        finished_at: timestamp(finished_at),
+        duration_ms: duration.as_millis() as u64,
+        resource_usage,
+        cwd: working_dir(options),
+        env: env_names(options),
+        env_cleared: options.clear_env,
+        pty: options.pty,
+        sequence_id: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Stdio};
+    use std::sync::Arc;
+
+    use super::{
+        exit_details, parse_env_file, parse_env_variable, run_cmd, stream_output, Launcher, Output,
+        RunOptions, SharedCapture, TIMEOUT_EXIT_CODE,
+    };
+
+    /// Whether the process is gone (or a zombie that's waiting to be reaped).
+    fn stopped(pid: &str) -> bool {
+        std::fs::read_to_string(format!("/proc/{pid}/stat"))
+            .map_or(true, |stat| stat.split_whitespace().nth(2) == Some("Z"))
+    }
+
+    #[test]
+    fn test_exit_details() {
+        let status = |raw| Ok(ExitStatus::from_raw(raw));
+
+        // exit codes are in the second byte of the raw status, signals in the first (0x80: core dumped)
+        assert_eq!(exit_details(&status(0), true), (0, None, false, false));
+        assert_eq!(exit_details(&status(3 << 8), true), (3, None, false, false));
+        assert_eq!(
+            exit_details(&status(libc::SIGKILL), false),
+            (137, Some(9), false, false)
+        );
+        assert_eq!(
+            exit_details(&status(libc::SIGSEGV | 0x80), true),
+            (139, Some(11), true, false)
+        );
+        assert_eq!(
+            exit_details(&status(139 << 8), true),
+            (139, Some(11), false, true)
+        );
+        assert_eq!(
+            exit_details(&status(139 << 8), false),
+            (139, None, false, false)
+        );
+        assert_eq!(
+            exit_details(&status(128 << 8), true),
+            (128, None, false, false)
+        );
+        assert_eq!(
+            exit_details(&status(255 << 8), true),
+            (255, None, false, false)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_signal_of_compound_command() {
+        let args = [String::from("sh -c 'kill -USR1 $$' && echo not reached")];
+        let result = run_cmd(&args, &RunOptions::default(), None).await.unwrap();
+
+        assert_eq!(result.exit_code, 128 + libc::SIGUSR1);
+        assert_eq!(result.signal, Some(libc::SIGUSR1));
+        assert_eq!(result.signal_name.as_deref(), Some("SIGUSR1"));
+        assert!(result.signal_inferred);
+        assert_eq!(
+            result.termination().as_deref(),
+            Some("exited with 138 (likely killed by SIGUSR1)")
+        );
+
+        // without a shell, the signal is what the system reports
+        let options = RunOptions {
+            launcher: Launcher::Exec,
+            ..RunOptions::default()
+        };
+        let args = ["sh", "-c", "kill -USR1 $$"].map(String::from);
+        let result = run_cmd(&args, &options, None).await.unwrap();
+
+        assert_eq!(result.signal, Some(libc::SIGUSR1));
+        assert!(!result.signal_inferred);
+        assert_eq!(result.termination().as_deref(), Some("killed by SIGUSR1"));
+    }
+
+    #[tokio::test]
+    async fn test_exit_code_without_shell() {
+        let options = RunOptions {
+            launcher: Launcher::Exec,
+            ..RunOptions::default()
+        };
 
-    Ok(result)
+        // like `--exec sh -c 'exit 130'`: an exit code, not a signal
+        let args = ["sh", "-c", "exit 130"].map(String::from);
+        let result = run_cmd(&args, &options, None).await.unwrap();
+
+        assert_eq!(result.exit_code, 130);
+        assert_eq!(result.signal, None);
+        assert_eq!(result.termination(), None);
+        assert_eq!(result.status_text(), "failed with exit code 130");
+    }
+
+    #[tokio::test]
+    async fn test_stream_output() {
+        let mut child = tokio::process::Command::new("sh")
+            .args([
+                "-c",
+                "printf a; sleep 0.1; printf b >&2; sleep 0.1; printf 'c\\n'",
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let capture = SharedCapture::default();
+        let mut forwarded = Vec::new();
+        stream_output(
+            child.stdout.take(),
+            child.stderr.take(),
+            Arc::clone(&capture),
+            |output, chunk: &[u8]| {
+                forwarded.push((output, String::from_utf8_lossy(chunk).into_owned()));
+            },
+        )
+        .await;
+        child.wait().await.unwrap();
+
+        // forwarded as it arrived, and captured as a whole
+        let expected = [
+            (Output::Stdout, "a"),
+            (Output::Stderr, "b"),
+            (Output::Stdout, "c\n"),
+        ];
+        assert_eq!(
+            forwarded,
+            expected.map(|(output, text)| (output, text.to_string()))
+        );
+
+        assert_eq!(capture.lock().unwrap().stdout, b"ac\n");
+        assert_eq!(capture.lock().unwrap().stderr, b"b");
+    }
+
+    #[tokio::test]
+    async fn test_capture() {
+        // the logger of the tests is quiet (like `-q`), which only stops the forwarding
+        let args = [String::from(
+            "printf a; printf b >&2; printf c; seq 100000 >&2",
+        )];
+        let result = run_cmd(&args, &RunOptions::default(), None).await.unwrap();
+
+        let expected_stderr: String = (1..=100_000).map(|n| n.to_string() + "\n").collect();
+        assert_eq!(result.stdout, "ac");
+        assert_eq!(result.stderr, format!("b{expected_stderr}"));
+    }
+
+    #[tokio::test]
+    async fn test_cwd_and_env() {
+        let cwd = std::env::temp_dir();
+        let options = RunOptions {
+            cwd: Some(cwd.clone()),
+            env: vec![(String::from("FOO"), String::from("secret"))],
+            ..RunOptions::default()
+        };
+
+        // without the value in the command, which is recorded as well
+        let args = [String::from("pwd; test ${#FOO} -eq 6")];
+        let result = run_cmd(&args, &options, None).await.unwrap();
+
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.stdout.trim(), cwd.to_string_lossy());
+        assert_eq!(result.cwd.as_deref(), Some(&*cwd.to_string_lossy()));
+        assert_eq!(result.env, ["FOO"]);
+        // only the name is recorded
+        assert!(!serde_json::to_string(&result).unwrap().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_stops_process_group() {
+        let options = RunOptions {
+            timeout: Some(Duration::from_millis(300)),
+            kill_after: Duration::from_secs(1),
+            ..RunOptions::default()
+        };
+
+        let args = [String::from("sleep 100 & echo $!; wait")];
+        let result = run_cmd(&args, &options, None).await.unwrap();
+        assert_eq!(result.exit_code, TIMEOUT_EXIT_CODE);
+
+        let pid = result.stdout.trim();
+        assert!(!pid.is_empty());
+
+        for _ in 0..20 {
+            if stopped(pid) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("`sleep 100` ({pid}) is still running after the timeout");
+    }
+
+    #[tokio::test]
+    async fn test_process_group() {
+        // the 5th field of /proc/<pid>/stat is the process group
+        let args = [String::from("cut -d' ' -f5 /proc/$$/stat")];
+        // SAFETY: `getpgrp` always succeeds.
+        let own_group = unsafe { libc::getpgrp() }.to_string();
+
+        // only a command that `--timeout` may have to stop gets a group of its own
+        let result = run_cmd(&args, &RunOptions::default(), None).await.unwrap();
+        assert_eq!(result.stdout.trim(), own_group);
+
+        let options = RunOptions {
+            timeout: Some(Duration::from_mins(1)),
+            ..RunOptions::default()
+        };
+        let result = run_cmd(&args, &options, None).await.unwrap();
+        assert_ne!(result.stdout.trim(), own_group);
+    }
+
+    #[tokio::test]
+    async fn test_pty_session() {
+        let options = RunOptions {
+            pty: true,
+            ..RunOptions::default()
+        };
+
+        // a terminal on stdout, that is also the controlling terminal of a session of its own
+        let args = [String::from(
+            "[ -t 1 ] && : < /dev/tty && cut -d' ' -f1,6 /proc/$$/stat",
+        )];
+        let result = run_cmd(&args, &options, None).await.unwrap();
+        assert_eq!(result.exit_code, 0, "{}", result.stdout);
+
+        let ids: Vec<&str> = result.stdout.split_whitespace().collect();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let contents = "# database\nDB_HOST=localhost\n\nexport DB_NAME=\"app\"\nGREETING='hello world'\nEMPTY=\nURL=https://x.com/?a=b\n";
+
+        let variables = parse_env_file(contents).unwrap();
+        let expected = [
+            ("DB_HOST", "localhost"),
+            ("DB_NAME", "app"),
+            ("GREETING", "hello world"),
+            ("EMPTY", ""),
+            ("URL", "https://x.com/?a=b"),
+        ];
+
+        assert_eq!(variables.len(), expected.len());
+        for ((key, value), (expected_key, expected_value)) in variables.iter().zip(expected) {
+            assert_eq!(
+                (key.as_str(), value.as_str()),
+                (expected_key, expected_value)
+            );
+        }
+
+        assert_eq!(
+            parse_env_file("A=1\nnot a variable").unwrap_err(),
+            "line 2: expected `KEY=VALUE`, got `not a variable`"
+        );
+        assert!(parse_env_variable("=value").is_err());
+        assert!(parse_env_variable("MY KEY=value").is_err());
+    }
 }
@@ -0,0 +1,1124 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, ValueEnum};
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+use url::Url;
+
+use crate::cli::Cli;
+use crate::command::CommandResult;
+use crate::constants::{
+    CONFIG_FILE_NAME, DEFAULT_ATTACH_THRESHOLD, DEFAULT_ENCRYPTED_TITLE, DEFAULT_FLAP_THRESHOLD,
+    DEFAULT_FLAP_WINDOW, DEFAULT_NTFY_SERVER, DEFAULT_SECONDARY_TOPIC, DEFAULT_SEND_RETRIES,
+    DEFAULT_TAIL_CONTEXT, DEFAULT_TAIL_LINES, DEFAULT_TAIL_POLL_INTERVAL, DEFAULT_TAIL_RATE_LIMIT,
+    SYSTEM_CONFIG_DIR,
+};
+use crate::format::MessageFormat;
+use crate::helpers::{duration_string, normalize_url};
+use crate::ntfy::{parse_action, Action, Credentials, PriorityLevel};
+use crate::outcome::Outcome;
+use crate::spool::{default_spool_dir, Spool};
+use crate::state::{default_state_dir, NotifyMode, StateStore};
+
+/// Keys that are never printed by `config show`.
+const SECRET_KEYS: [&str; 3] = ["token", "password", "key"];
+
+/// Where a (merged) config value came from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Profile { name: String, path: PathBuf },
+    Env(String),
+    Cli,
+}
+
+impl fmt::Display for Source {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Profile { name, path } => write!(f, "profile '{name}' in {}", path.display()),
+            Self::Env(name) => write!(f, "env {name}"),
+            Self::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+/// Priority per outcome of the command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrioritySettings {
+    pub success: PriorityLevel,
+    pub warning: PriorityLevel,
+    pub failure: PriorityLevel,
+    pub timeout: PriorityLevel,
+}
+
+impl Default for PrioritySettings {
+    fn default() -> Self {
+        Self {
+            success: PriorityLevel::Default,
+            warning: PriorityLevel::Default,
+            failure: PriorityLevel::High,
+            timeout: PriorityLevel::Max,
+        }
+    }
+}
+
+impl PrioritySettings {
+    pub const fn for_result(
+        &self,
+        result: &CommandResult,
+    ) -> PriorityLevel {
+        match result.outcome {
+            Outcome::Success => self.success,
+            Outcome::Warning => self.warning,
+            Outcome::Failure => self.failure,
+            Outcome::Timeout => self.timeout,
+        }
+    }
+}
+
+/// Tags (emoji shortcodes) per outcome of the command, added to `tags`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoTagSettings {
+    pub enabled: bool,
+    pub success: Vec<String>,
+    pub warning: Vec<String>,
+    pub failure: Vec<String>,
+    pub timeout: Vec<String>,
+}
+
+impl Default for AutoTagSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            success: vec![String::from("white_check_mark")],
+            warning: vec![String::from("warning")],
+            failure: vec![String::from("rotating_light")],
+            timeout: vec![String::from("hourglass")],
+        }
+    }
+}
+
+impl AutoTagSettings {
+    pub fn for_result(
+        &self,
+        result: &CommandResult,
+    ) -> &[String] {
+        if !self.enabled {
+            return &[];
+        }
+
+        match result.outcome {
+            Outcome::Success => &self.success,
+            Outcome::Warning => &self.warning,
+            Outcome::Failure => &self.failure,
+            Outcome::Timeout => &self.timeout,
+        }
+    }
+}
+
+/// Buttons that are only added when the command failed or timed out. All values are templates (like `--title`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailureActionSettings {
+    /// "View logs" opens this url, e.g. `https://ci.example.com/logs?job={{ command | urlencode }}`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub view_logs: Option<String>,
+    /// "Re-run" sends a `POST` request to this webhook.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rerun: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rerun_body: Option<String>,
+    /// "Ack" publishes to this topic on `endpoint` (it has to be writable without credentials), skipped when
+    /// `endpoint` is not an ntfy server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ack_topic: Option<String>,
+}
+
+/// What is sent to the secondary topic.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SecondaryContent {
+    /// Only the title, as the message
+    #[default]
+    Title,
+    /// The title, with the status and duration as the message
+    Summary,
+    /// The same message as the primary topic
+    Full,
+}
+
+/// Name of the `{suffix}` per outcome of the command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SuffixSettings {
+    pub success: String,
+    pub warning: String,
+    pub failure: String,
+    pub timeout: String,
+}
+
+impl Default for SuffixSettings {
+    fn default() -> Self {
+        Self {
+            success: String::from("success"),
+            warning: String::from("warning"),
+            failure: String::from("failure"),
+            timeout: String::from("timeout"),
+        }
+    }
+}
+
+impl SuffixSettings {
+    pub fn for_result(
+        &self,
+        result: &CommandResult,
+    ) -> &str {
+        match result.outcome {
+            Outcome::Success => &self.success,
+            Outcome::Warning => &self.warning,
+            Outcome::Failure => &self.failure,
+            Outcome::Timeout => &self.timeout,
+        }
+    }
+}
+
+/// Send results with a specific exit code to another suffix or topic (e.g. exit code 3 to `{topic}--warning`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingRule {
+    pub exit_code: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    /// Replaces `secondary.topic` for this exit code, may also contain `{topic}` and `{suffix}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+}
+
+/// Behaviour of the `{topic}--success`/`{topic}--failure` messages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecondarySettings {
+    pub enabled: bool,
+    /// Where to send it: `{topic}` is replaced by the primary topic and `{suffix}` by the outcome.
+    pub topic: String,
+    pub content: SecondaryContent,
+
+    /// Send to another server (with its own credentials) instead of `endpoint`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+
+    pub suffixes: SuffixSettings,
+    /// The first rule matching the exit code wins.
+    pub rules: Vec<RoutingRule>,
+}
+
+impl Default for SecondarySettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            topic: String::from(DEFAULT_SECONDARY_TOPIC),
+            content: SecondaryContent::default(),
+            endpoint: None,
+            token: None,
+            user: None,
+            password: None,
+            suffixes: SuffixSettings::default(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl SecondarySettings {
+    /// The secondary topic for a result, based on the routing rules, suffixes and topic pattern.
+    #[allow(clippy::literal_string_with_formatting_args)] // placeholders in the pattern, not `format!` arguments
+    pub fn topic_for(
+        &self,
+        topic: &str,
+        result: &CommandResult,
+    ) -> String {
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| rule.exit_code == result.exit_code);
+
+        let suffix = rule
+            .and_then(|rule| rule.suffix.as_deref())
+            .unwrap_or_else(|| self.suffixes.for_result(result));
+
+        let pattern = rule
+            .and_then(|rule| rule.topic.as_deref())
+            .unwrap_or(&self.topic);
+
+        pattern
+            .replace("{topic}", topic)
+            .replace("{suffix}", suffix)
+    }
+
+    pub fn credentials(&self) -> Option<Credentials> {
+        Credentials::from_options(
+            self.token.as_deref(),
+            self.user.as_deref(),
+            self.password.as_deref(),
+        )
+    }
+}
+
+/// A server that `--to` targets can refer to by name (`<name>/<topic>`), with its own credentials.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerSettings {
+    pub endpoint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+impl ServerSettings {
+    pub fn credentials(&self) -> Option<Credentials> {
+        Credentials::from_options(
+            self.token.as_deref(),
+            self.user.as_deref(),
+            self.password.as_deref(),
+        )
+    }
+}
+
+/// When to notify, and when a command is considered to be flapping.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifySettings {
+    pub mode: NotifyMode,
+    /// Number of recent runs that are remembered per command.
+    pub flap_window: usize,
+    /// Number of outcome changes within the window after which a command is flapping (0 to disable).
+    pub flap_threshold: usize,
+    /// Where the outcomes are remembered, defaults to `~/.local/state/ntfy-log/jobs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_dir: Option<PathBuf>,
+}
+
+impl Default for NotifySettings {
+    fn default() -> Self {
+        Self {
+            mode: NotifyMode::default(),
+            flap_window: DEFAULT_FLAP_WINDOW,
+            flap_threshold: DEFAULT_FLAP_THRESHOLD,
+            state_dir: None,
+        }
+    }
+}
+
+/// Lines in followed files that trigger a notification (`ntfy-log tail`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TailRule {
+    /// Regular expression that is matched against every line.
+    pub pattern: String,
+    /// Used in the title instead of the pattern.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default = "default_tail_priority")]
+    pub priority: PriorityLevel,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+const fn default_tail_priority() -> PriorityLevel {
+    PriorityLevel::High
+}
+
+/// Settings of `ntfy-log tail`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TailSettings {
+    pub rules: Vec<TailRule>,
+    /// Lines of context before a matching line.
+    pub before: usize,
+    /// Lines of context after a matching line.
+    pub after: usize,
+    /// Per rule, at most one notification is sent per period, later matches are summarized at its end.
+    #[serde(with = "duration_string")]
+    pub rate_limit: Duration,
+    /// How often the files are checked for new lines.
+    #[serde(with = "duration_string")]
+    pub poll_interval: Duration,
+}
+
+impl Default for TailSettings {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            before: DEFAULT_TAIL_CONTEXT,
+            after: DEFAULT_TAIL_CONTEXT,
+            rate_limit: DEFAULT_TAIL_RATE_LIMIT,
+            poll_interval: DEFAULT_TAIL_POLL_INTERVAL,
+        }
+    }
+}
+
+/// Rules that decide whether a command succeeded, beyond its exit code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutcomeSettings {
+    /// Exit codes that count as success.
+    pub ok_exit_codes: Vec<i32>,
+    /// Regular expressions (`^` and `$` match at every line) that turn a success into a failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fail_if_stdout_matches: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fail_if_stderr_matches: Option<String>,
+    /// Regular expressions that turn a success into a warning.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warn_if_stdout_matches: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warn_if_stderr_matches: Option<String>,
+    pub warn_if_stderr_nonempty: bool,
+}
+
+impl Default for OutcomeSettings {
+    fn default() -> Self {
+        Self {
+            ok_exit_codes: vec![0],
+            fail_if_stdout_matches: None,
+            fail_if_stderr_matches: None,
+            warn_if_stdout_matches: None,
+            warn_if_stderr_matches: None,
+            warn_if_stderr_nonempty: false,
+        }
+    }
+}
+
+impl OutcomeSettings {
+    /// Whether any rule looks at stderr (which is empty with `--pty`).
+    pub const fn uses_stderr(&self) -> bool {
+        self.fail_if_stderr_matches.is_some()
+            || self.warn_if_stderr_matches.is_some()
+            || self.warn_if_stderr_nonempty
+    }
+}
+
+/// Secrets that are replaced before anything is sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedactSettings {
+    pub enabled: bool,
+    /// Use the built-in detectors (AWS keys, bearer tokens, `password=...`, private keys, URL userinfo, ...).
+    pub builtin: bool,
+    /// Additional regular expressions, only the `secret` group is replaced if a pattern has one.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub patterns: Vec<String>,
+}
+
+impl Default for RedactSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            builtin: true,
+            patterns: Vec::new(),
+        }
+    }
+}
+
+/// End-to-end encryption of everything that is sent, with a shared key (see `ntfy-log decrypt`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionSettings {
+    /// Base64 encoded 256-bit key, takes precedence over a `key_file` in the same file
+    /// (a `key_file` from a profile or the command line replaces it).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// File that contains the (base64 encoded) key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+    /// Title of encrypted messages, in cleartext.
+    pub title: String,
+}
+
+impl Default for EncryptionSettings {
+    fn default() -> Self {
+        Self {
+            key: None,
+            key_file: None,
+            title: String::from(DEFAULT_ENCRYPTED_TITLE),
+        }
+    }
+}
+
+/// Queue for notifications that could not be delivered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpoolSettings {
+    pub enabled: bool,
+    /// Defaults to `~/.local/state/ntfy-log/spool`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir: Option<PathBuf>,
+    /// Number of immediate retries before a notification is queued.
+    pub retries: u32,
+}
+
+impl Default for SpoolSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: None,
+            retries: DEFAULT_SEND_RETRIES,
+        }
+    }
+}
+
+/// When (and how) large output is sent as attachments instead of in the message body.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AttachmentSettings {
+    pub enabled: bool,
+    /// Maximum size (in bytes) of the message body before the output is attached instead.
+    pub threshold: usize,
+    /// Number of lines of stdout/stderr that are still shown in the message body.
+    pub tail_lines: usize,
+    pub gzip: bool,
+}
+
+impl Default for AttachmentSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: DEFAULT_ATTACH_THRESHOLD,
+            tail_lines: DEFAULT_TAIL_LINES,
+            gzip: false,
+        }
+    }
+}
+
+/// Everything that can be set in a config file (or profile), with the built-in defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub endpoint: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+
+    /// Title of the message, may be a template (e.g. `{{ command }} on {{ host.hostname }}`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub format: MessageFormat,
+    /// Template for `format = "template"`, either inline or `@path/to/file`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+
+    /// Tags (emoji shortcodes) of every message.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Opened when the notification is clicked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub click: Option<String>,
+    /// Url of the notification icon.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    /// Buttons in ntfy's short format, e.g. `view, Open dashboard, https://example.com`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<String>,
+    /// Deliver later: a duration (from when the message is sent) like `30m`, or a time like `10am`,
+    /// `tomorrow, 8am` or a unix timestamp (as understood by ntfy).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay: Option<String>,
+    /// Also forward the message to this email address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+
+    /// Named lists of `--to` targets, e.g. `oncall = ["ntfy.sh/ops", "selfhosted/ops-archive"]`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, Vec<String>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub servers: BTreeMap<String, ServerSettings>,
+
+    pub auto_tags: AutoTagSettings,
+    pub failure_actions: FailureActionSettings,
+    pub outcome: OutcomeSettings,
+    pub redact: RedactSettings,
+    pub encryption: EncryptionSettings,
+    pub priority: PrioritySettings,
+    pub notify: NotifySettings,
+    pub secondary: SecondarySettings,
+    pub spool: SpoolSettings,
+    pub attachments: AttachmentSettings,
+    pub tail: TailSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            endpoint: DEFAULT_NTFY_SERVER.to_string(),
+            token: None,
+            user: None,
+            password: None,
+            title: None,
+            format: MessageFormat::default(),
+            template: None,
+            tags: Vec::new(),
+            click: None,
+            icon: None,
+            actions: Vec::new(),
+            delay: None,
+            email: None,
+            groups: BTreeMap::new(),
+            servers: BTreeMap::new(),
+            auto_tags: AutoTagSettings::default(),
+            failure_actions: FailureActionSettings::default(),
+            outcome: OutcomeSettings::default(),
+            redact: RedactSettings::default(),
+            encryption: EncryptionSettings::default(),
+            priority: PrioritySettings::default(),
+            notify: NotifySettings::default(),
+            secondary: SecondarySettings::default(),
+            spool: SpoolSettings::default(),
+            attachments: AttachmentSettings::default(),
+            tail: TailSettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn click_url(&self) -> Result<Option<Url>, String> {
+        self.click
+            .as_deref()
+            .map(|click| {
+                Url::parse(click).map_err(|error| format!("Invalid `click` url '{click}': {error}"))
+            })
+            .transpose()
+    }
+
+    pub fn parsed_actions(&self) -> Result<Vec<Action>, String> {
+        self.actions
+            .iter()
+            .map(|action| parse_action(action))
+            .collect()
+    }
+
+    /// Check the values that can't be checked while deserializing.
+    fn validate(&self) -> Result<(), String> {
+        self.click_url()?;
+        self.parsed_actions()?;
+        Ok(())
+    }
+
+    pub fn credentials(&self) -> Option<Credentials> {
+        Credentials::from_options(
+            self.token.as_deref(),
+            self.user.as_deref(),
+            self.password.as_deref(),
+        )
+    }
+
+    /// The server the secondary messages are sent to (`primary` unless `secondary.endpoint` is set).
+    pub fn secondary_endpoint<'a>(
+        &'a self,
+        primary: &'a str,
+    ) -> &'a str {
+        self.secondary.endpoint.as_deref().unwrap_or(primary)
+    }
+
+    /// Credentials for one of the configured servers: the main credentials are only sent to `endpoint`,
+    /// the secondary ones to `secondary.endpoint` and those of `[servers]` to their own server.
+    pub fn credentials_for(
+        &self,
+        endpoint: &str,
+    ) -> Option<Credentials> {
+        let server = self
+            .servers
+            .values()
+            .find(|server| same_server(&server.endpoint, endpoint));
+
+        match (&self.secondary.endpoint, server) {
+            _ if same_server(endpoint, &self.endpoint) => self.credentials(),
+            (Some(secondary), _) if same_server(endpoint, secondary) => {
+                self.secondary.credentials()
+            },
+            (_, Some(server)) => server.credentials(),
+            _ => None,
+        }
+    }
+
+    /// Where the last outcomes of commands are remembered (for `notify.mode`).
+    pub fn state_store(&self) -> Option<StateStore> {
+        self.notify
+            .state_dir
+            .clone()
+            .or_else(default_state_dir)
+            .map(StateStore::new)
+    }
+
+    /// `None` if spooling is disabled (or no spool directory could be determined).
+    pub fn spool(&self) -> Option<Spool> {
+        if !self.spool.enabled {
+            return None;
+        }
+
+        self.spool
+            .dir
+            .clone()
+            .or_else(default_spool_dir)
+            .map(Spool::new)
+    }
+}
+
+/// Whether two endpoints are the same server (e.g. `ntfy.sh` and `https://ntfy.sh/`).
+pub fn same_server(
+    left: &str,
+    right: &str,
+) -> bool {
+    normalize_url(left, DEFAULT_NTFY_SERVER) == normalize_url(right, DEFAULT_NTFY_SERVER)
+}
+
+/// Values from one place (defaults, a config file, a profile, env or cli), lowest priority first.
+struct Layer {
+    source: Source,
+    values: Table,
+}
+
+/// Settings merged from all layers, remembering where each value came from.
+pub struct Config {
+    pub settings: Settings,
+    pub profile: Option<String>,
+    pub files: Vec<PathBuf>,
+    sources: BTreeMap<String, Source>,
+}
+
+pub fn system_config_path() -> PathBuf {
+    Path::new(SYSTEM_CONFIG_DIR).join(CONFIG_FILE_NAME)
+}
+
+/// `$XDG_CONFIG_HOME/ntfy-log/config.toml`, falling back to `~/.config/ntfy-log/config.toml`.
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(
+        config_home
+            .join(env!("CARGO_PKG_NAME"))
+            .join(CONFIG_FILE_NAME),
+    )
+}
+
+/// Make sure a layer only contains known keys with valid values, before it is merged with the others.
+fn validate(
+    values: &Table,
+    source: &Source,
+) -> Result<(), String> {
+    Settings::deserialize(Value::Table(values.clone()))
+        .map(|_| ())
+        .map_err(|error| format!("Invalid config ({source}): {}", error.message()))
+}
+
+/// Read a config file into its base layer and (if selected and present) its profile layer.
+fn read_config_file(
+    path: &Path,
+    profile: Option<&str>,
+) -> Result<(Option<Layer>, Option<Layer>), String> {
+    if !path.exists() {
+        return Ok((None, None));
+    }
+
+    let contents = fs::read_to_string(path)
+        .map_err(|error| format!("Could not read {}: {error}", path.display()))?;
+
+    let mut values: Table = toml::from_str(&contents)
+        .map_err(|error| format!("Invalid config ({}): {}", path.display(), error.message()))?;
+
+    let mut profiles = match values.remove("profile") {
+        None => Table::new(),
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => {
+            return Err(format!(
+                "Invalid config ({}): `profile` should be a table",
+                path.display()
+            ))
+        },
+    };
+
+    let base = Layer {
+        source: Source::File(path.to_path_buf()),
+        values,
+    };
+    validate(&base.values, &base.source)?;
+
+    let profile_layer = match profile.and_then(|name| profiles.remove(name).map(|v| (name, v))) {
+        None => None,
+        Some((name, Value::Table(values))) => {
+            let layer = Layer {
+                source: Source::Profile {
+                    name: name.to_string(),
+                    path: path.to_path_buf(),
+                },
+                values,
+            };
+            validate(&layer.values, &layer.source)?;
+            Some(layer)
+        },
+        Some((name, _)) => {
+            return Err(format!(
+                "Invalid config ({}): `profile.{name}` should be a table",
+                path.display()
+            ))
+        },
+    };
+
+    Ok((Some(base), profile_layer))
+}
+
+/// Values that were passed on the command line or via env, one layer per value.
+fn cli_layers(
+    args: &Cli,
+    matches: &ArgMatches,
+) -> Vec<Layer> {
+    let command = Cli::command();
+    let mut env_layers = Vec::new();
+    let mut cli_layers = Vec::new();
+
+    for (id, key, value) in args.config_overrides() {
+        let mut values = Table::new();
+        insert_path(&mut values, key, value);
+
+        let env_name = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id)
+            .and_then(|arg| arg.get_env())
+            .map(|name| name.to_string_lossy().into_owned());
+
+        match (matches.value_source(id), env_name) {
+            (Some(ValueSource::EnvVariable), Some(name)) => env_layers.push(Layer {
+                source: Source::Env(name),
+                values,
+            }),
+            _ => cli_layers.push(Layer {
+                source: Source::Cli,
+                values,
+            }),
+        }
+    }
+
+    env_layers.extend(cli_layers);
+    env_layers
+}
+
+/// Set a (dotted) key in a nested table, creating intermediate tables if required.
+fn insert_path(
+    table: &mut Table,
+    key: &str,
+    value: Value,
+) {
+    match key.split_once('.') {
+        None => {
+            table.insert(key.to_string(), value);
+        },
+        Some((head, rest)) => {
+            let entry = table
+                .entry(head)
+                .or_insert_with(|| Value::Table(Table::new()));
+
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+
+            if let Value::Table(inner) = entry {
+                insert_path(inner, rest, value);
+            }
+        },
+    }
+}
+
+/// All leaf values of a table as (dotted key, value).
+fn flatten(
+    table: &Table,
+    prefix: &str,
+    into: &mut Vec<(String, Value)>,
+) {
+    for (key, value) in table {
+        let full_key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{prefix}.{key}")
+        };
+
+        match value {
+            Value::Table(inner) => flatten(inner, &full_key, into),
+            _ => into.push((full_key, value.clone())),
+        }
+    }
+}
+
+/// Keys that configure the same thing, a layer that sets one of them unsets the other.
+const EXCLUSIVE_KEYS: [(&str, &str); 1] = [("encryption.key", "encryption.key_file")];
+
+fn remove_path(
+    table: &mut Table,
+    key: &str,
+) {
+    match key.split_once('.') {
+        None => {
+            table.remove(key);
+        },
+        Some((head, rest)) => {
+            if let Some(Value::Table(inner)) = table.get_mut(head) {
+                remove_path(inner, rest);
+            }
+        },
+    }
+}
+
+fn lookup<'a>(
+    table: &'a Table,
+    key: &str,
+) -> Option<&'a Value> {
+    match key.split_once('.') {
+        None => table.get(key),
+        Some((head, rest)) => lookup(table.get(head)?.as_table()?, rest),
+    }
+}
+
+impl Config {
+    /// Merge the built-in defaults, `/etc/ntfy-log/config.toml`, the user's config file,
+    /// the selected profile, env variables and command line options (in that order).
+    pub fn load(
+        args: &Cli,
+        matches: &ArgMatches,
+    ) -> Result<Self, String> {
+        let profile = args.profile.as_deref();
+
+        let mut files = vec![system_config_path()];
+        files.extend(user_config_path());
+
+        let mut base_layers = Vec::new();
+        let mut profile_layers = Vec::new();
+
+        for path in &files {
+            let (base, profile_layer) = read_config_file(path, profile)?;
+            base_layers.extend(base);
+            profile_layers.extend(profile_layer);
+        }
+
+        if let Some(name) = profile {
+            if profile_layers.is_empty() {
+                return Err(format!("Unknown profile '{name}'"));
+            }
+        }
+
+        let default_values = Table::try_from(Settings::default()).map_err(|e| e.to_string())?;
+
+        let mut layers = vec![Layer {
+            source: Source::Default,
+            values: default_values,
+        }];
+        layers.extend(base_layers);
+        layers.extend(profile_layers);
+        layers.extend(cli_layers(args, matches));
+
+        let mut config = Self::from_layers(layers)?;
+        config.profile = profile.map(String::from);
+        config.files = files;
+        Ok(config)
+    }
+
+    fn from_layers(layers: Vec<Layer>) -> Result<Self, String> {
+        let mut merged = Table::new();
+        let mut sources = BTreeMap::new();
+
+        for layer in layers {
+            let mut values = Vec::new();
+            flatten(&layer.values, "", &mut values);
+
+            // e.g. `--encryption-key-file` replaces a `key` from the config file
+            for pair in EXCLUSIVE_KEYS {
+                for (key, other) in [pair, (pair.1, pair.0)] {
+                    if lookup(&layer.values, key).is_some()
+                        && lookup(&layer.values, other).is_none()
+                    {
+                        remove_path(&mut merged, other);
+                        sources.remove(other);
+                    }
+                }
+            }
+
+            for (key, value) in values {
+                insert_path(&mut merged, &key, value);
+                sources.insert(key, layer.source.clone());
+            }
+        }
+
+        let settings = Settings::deserialize(Value::Table(merged))
+            .map_err(|error| format!("Invalid config: {}", error.message()))?;
+        settings
+            .validate()
+            .map_err(|error| format!("Invalid config: {error}"))?;
+
+        Ok(Self {
+            settings,
+            profile: None,
+            files: Vec::new(),
+            sources,
+        })
+    }
+
+    /// Human-readable overview of the merged config, with the source of every value (`config show`).
+    pub fn show(&self) -> String {
+        let merged = Table::try_from(&self.settings).unwrap_or_default();
+
+        let mut lines = Vec::new();
+        for path in &self.files {
+            let state = if path.exists() { "" } else { " (not found)" };
+            lines.push(format!("# config file: {}{state}", path.display()));
+        }
+
+        if let Some(profile) = &self.profile {
+            lines.push(format!("# profile: {profile}"));
+        }
+
+        let values: Vec<(String, &Source)> = self
+            .sources
+            .iter()
+            .filter_map(|(key, source)| {
+                let value = lookup(&merged, key)?;
+                let shown = if SECRET_KEYS.contains(&key.rsplit('.').next().unwrap_or(key)) {
+                    String::from("\"********\"")
+                } else {
+                    value.to_string()
+                };
+                Some((format!("{key} = {shown}"), source))
+            })
+            .collect();
+
+        let width = values.iter().map(|(line, _)| line.len()).max().unwrap_or(0);
+
+        for (line, source) in values {
+            lines.push(format!("{line:width$}  # {source}"));
+        }
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Layer, SecondarySettings, Settings, Source};
+    use crate::command::CommandResult;
+    use crate::ntfy::PriorityLevel;
+    use crate::outcome::Outcome;
+    use toml::Table;
+
+    fn layer(
+        source: Source,
+        contents: &str,
+    ) -> Layer {
+        Layer {
+            source,
+            values: toml::from_str::<Table>(contents).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_merge_order() {
+        let config = Config::from_layers(vec![
+            Layer {
+                source: Source::Default,
+                values: Table::try_from(Settings::default()).unwrap(),
+            },
+            layer(
+                Source::File("/etc/ntfy-log/config.toml".into()),
+                "endpoint = 'ntfy.example.com'\n[priority]\nfailure = 'urgent'",
+            ),
+            layer(Source::Cli, "endpoint = 'localhost'"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.settings.endpoint, "localhost");
+        assert_eq!(config.settings.priority.failure, PriorityLevel::Max);
+        assert_eq!(config.settings.priority.success, PriorityLevel::Default);
+        assert!(config.settings.secondary.enabled);
+
+        let shown = config.show();
+        assert!(shown.contains("endpoint = \"localhost\""));
+        assert!(shown.contains("# /etc/ntfy-log/config.toml"));
+        assert!(shown.contains("# command line"));
+    }
+
+    #[test]
+    fn test_exclusive_keys() {
+        let file = || {
+            layer(
+                Source::File("config.toml".into()),
+                "[encryption]\nkey = 'AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA='",
+            )
+        };
+
+        let config = Config::from_layers(vec![
+            file(),
+            layer(Source::Cli, "[encryption]\nkey_file = '/run/key'"),
+        ])
+        .unwrap();
+        assert_eq!(config.settings.encryption.key, None);
+        assert_eq!(config.settings.encryption.key_file, Some("/run/key".into()));
+        assert!(!config.show().contains("key = "));
+
+        let config =
+            Config::from_layers(vec![file(), layer(Source::Cli, "endpoint = 'localhost'")])
+                .unwrap();
+        assert!(config.settings.encryption.key.is_some());
+    }
+
+    #[test]
+    fn test_secondary_routing() {
+        let secondary: SecondarySettings = toml::from_str(
+            "topic = 'alerts-{suffix}'\n\
+             [suffixes]\nfailure = 'error'\n\
+             [[rules]]\nexit_code = 3\nsuffix = 'warning'\n\
+             [[rules]]\nexit_code = 4\ntopic = '{topic}-pager'",
+        )
+        .unwrap();
+
+        let mut result = CommandResult::from_stdin(String::new());
+        assert_eq!(secondary.topic_for("backup", &result), "alerts-success");
+
+        result.exit_code = 1;
+        result.outcome = Outcome::Failure;
+        assert_eq!(secondary.topic_for("backup", &result), "alerts-error");
+
+        result.exit_code = 3;
+        assert_eq!(secondary.topic_for("backup", &result), "alerts-warning");
+
+        result.exit_code = 4;
+        assert_eq!(secondary.topic_for("backup", &result), "backup-pager");
+
+        let default = SecondarySettings::default();
+        result.outcome = Outcome::Timeout;
+        result.exit_code = 124;
+        assert_eq!(default.topic_for("backup", &result), "backup--timeout");
+    }
+}
@@ -1,3 +1,46 @@
+use std::time::Duration;
+
 pub const DEFAULT_NTFY_SERVER: &str = "https://ntfy.sh";
 pub const DEFAULT_SCHEMA: &str = "https://";
 pub const GITHUB_REPO: &str = "robinvandernoord/ntfy-log";
+pub const DEFAULT_KILL_AFTER: &str = "10s";
+pub const DEFAULT_BATCH_LINES: usize = 50;
+pub const DEFAULT_BATCH_INTERVAL: &str = "10s";
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/ntfy-log";
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+pub const DEFAULT_SEND_RETRIES: u32 = 2;
+/// Delay before the first in-process retry, doubled for every next retry.
+pub const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Backoff between attempts to deliver spooled notifications, doubled for every failed attempt.
+pub const SPOOL_BACKOFF_BASE: Duration = Duration::from_secs(30);
+pub const SPOOL_BACKOFF_MAX: Duration = Duration::from_hours(1);
+/// Queued notifications that still can't be delivered after this long are set aside.
+pub const SPOOL_MAX_AGE: Duration = Duration::from_hours(7 * 24);
+/// ntfy.sh turns messages over 4096 bytes into attachments itself (or rejects them), so stay below that.
+pub const DEFAULT_ATTACH_THRESHOLD: usize = 4096;
+pub const DEFAULT_TAIL_LINES: usize = 20;
+/// `{topic}` is replaced by the primary topic, `{suffix}` by `success`, `failure` or `timeout`.
+pub const DEFAULT_SECONDARY_TOPIC: &str = "{topic}--{suffix}";
+pub const DEFAULT_FLAP_WINDOW: usize = 10;
+/// A command that changed outcome this many times within the window is flapping (and muted until it settles).
+pub const DEFAULT_FLAP_THRESHOLD: usize = 5;
+/// Time a job with `--heartbeat` may be late before `heartbeat check` alerts.
+pub const DEFAULT_HEARTBEAT_GRACE: &str = "5m";
+/// Lines of stdout/stderr (limited to `PROGRESS_MAX_BYTES` each) in `--progress-every` updates.
+pub const PROGRESS_LINES: usize = 5;
+pub const PROGRESS_MAX_BYTES: usize = 1024;
+pub const DEFAULT_TAIL_CONTEXT: usize = 3;
+pub const DEFAULT_TAIL_RATE_LIMIT: Duration = Duration::from_mins(1);
+pub const DEFAULT_TAIL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a match waits for its lines of context after it, before it's sent anyway.
+pub const TAIL_CONTEXT_WAIT: Duration = Duration::from_secs(2);
+/// ntfy rejects messages with more action buttons.
+pub const MAX_ACTIONS: usize = 3;
+/// Start of encrypted messages, followed by the base64 encoded nonce and ciphertext.
+pub const ENCRYPTED_PREFIX: &str = "ntfy-log:aes-256-gcm:";
+pub const DEFAULT_ENCRYPTED_TITLE: &str = "job finished (encrypted)";
+/// Chat services show long messages badly (or reject them), so only the end of the output is sent.
+pub const CHAT_MAX_BYTES: usize = 3000;
+pub const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// For a whole request, including the upload of an attachment.
+pub const HTTP_TIMEOUT: Duration = Duration::from_mins(1);
@@ -0,0 +1,239 @@
+use std::fs;
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::config::EncryptionSettings;
+use crate::constants::ENCRYPTED_PREFIX;
+use crate::helpers::ResultToString;
+use crate::ntfy::{Attachment, Payload, Priority};
+
+/// What is encrypted of a message: everything that could reveal what the command was or did.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub message: String,
+    #[serde(default)]
+    pub markdown: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub click: Option<String>,
+    /// The outcome tags and the priority of failures would give away how the command did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u8>,
+}
+
+/// AES-256-GCM with a shared key, every message gets a random nonce (stored in front of the ciphertext).
+pub struct Cipher {
+    key: LessSafeKey,
+    random: SystemRandom,
+}
+
+impl Cipher {
+    /// A base64 encoded 256-bit key (e.g. `head -c 32 /dev/urandom | base64`).
+    pub fn from_base64(key: &str) -> Result<Self, String> {
+        let bytes = BASE64
+            .decode(key.trim())
+            .map_err(|error| format!("Invalid encryption key: {error}"))?;
+
+        let key = UnboundKey::new(&AES_256_GCM, &bytes).map_err(|_| {
+            format!(
+                "Invalid encryption key: expected 32 bytes, got {}",
+                bytes.len()
+            )
+        })?;
+
+        Ok(Self {
+            key: LessSafeKey::new(key),
+            random: SystemRandom::new(),
+        })
+    }
+
+    /// `None` if no key is configured.
+    pub fn from_settings(settings: &EncryptionSettings) -> Result<Option<Self>, String> {
+        match (&settings.key, &settings.key_file) {
+            (Some(key), _) => Self::from_base64(key).map(Some),
+            (None, Some(path)) => Self::from_file(path).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Self, String> {
+        let key = fs::read_to_string(path).map_err(|error| {
+            format!("Could not read encryption key {}: {error}", path.display())
+        })?;
+
+        Self::from_base64(&key)
+    }
+
+    pub fn encrypt(
+        &self,
+        data: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let mut nonce = [0_u8; NONCE_LEN];
+        self.random
+            .fill(&mut nonce)
+            .map_err(|_| String::from("Could not generate a nonce"))?;
+
+        let mut sealed = data.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .map_err(|_| String::from("Encryption failed"))?;
+
+        Ok([nonce.as_slice(), &sealed].concat())
+    }
+
+    pub fn decrypt(
+        &self,
+        data: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        if data.len() < NONCE_LEN {
+            return Err(String::from("Not an encrypted message (too short)"));
+        }
+
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        // `split_at` already made sure the nonce has the right length
+        let nonce = Nonce::try_assume_unique_for_key(nonce).unwrap();
+
+        let mut opened = sealed.to_vec();
+        let length = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut opened)
+            .map_err(|_| String::from("Decryption failed (wrong key, or the message was altered)"))?
+            .len();
+
+        opened.truncate(length);
+        Ok(opened)
+    }
+
+    /// The payload with its title and message replaced by `title` and the encrypted envelope.
+    /// The click url, tags and priority move into the envelope (the message gets no tags and the default priority),
+    /// actions are dropped (their urls and bodies would be cleartext).
+    pub fn encrypt_payload(
+        &self,
+        payload: &Payload,
+        title: &str,
+    ) -> Result<Payload, String> {
+        let envelope = Envelope {
+            title: payload.title.clone(),
+            message: payload.message.clone(),
+            markdown: payload.markdown,
+            click: payload.click.as_ref().map(ToString::to_string),
+            tags: payload.tags.clone(),
+            priority: Some(payload.priority.clone() as u8),
+        };
+
+        let json = serde_json::to_vec(&envelope).map_err_to_string()?;
+        let message = format!("{ENCRYPTED_PREFIX}{}", BASE64.encode(self.encrypt(&json)?));
+
+        Ok(Payload {
+            title: Some(title.to_string()),
+            message,
+            markdown: false,
+            click: None,
+            actions: None,
+            tags: None,
+            priority: Priority::Default,
+            ..payload.clone()
+        })
+    }
+
+    /// Uploaded as `<filename>.enc`.
+    pub fn encrypt_attachment(
+        &self,
+        attachment: &Attachment,
+    ) -> Result<Attachment, String> {
+        Ok(Attachment {
+            filename: format!("{}.enc", attachment.filename),
+            data: self.encrypt(&attachment.data)?,
+        })
+    }
+
+    /// Decrypt a message that was sent by `encrypt_payload`.
+    pub fn decrypt_message(
+        &self,
+        message: &str,
+    ) -> Result<Envelope, String> {
+        let encoded = message
+            .trim()
+            .strip_prefix(ENCRYPTED_PREFIX)
+            .ok_or("Not an encrypted message")?;
+
+        let sealed = BASE64
+            .decode(encoded)
+            .map_err(|error| format!("Not an encrypted message: {error}"))?;
+
+        serde_json::from_slice(&self.decrypt(&sealed)?).map_err_to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cipher;
+    use crate::ntfy::{Action, ActionType, Attachment, Payload, Priority};
+
+    #[test]
+    fn test_round_trip() {
+        let cipher = Cipher::from_base64("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+
+        let payload = Payload::new("topic")
+            .title("backup.sh (3s)")
+            .message("secret output")
+            .markdown(true)
+            .tags(["x", "failure"])
+            .priority(Priority::High)
+            .click("https://ci.example.com/jobs/42".parse().unwrap())
+            .actions([Action::new(
+                ActionType::View,
+                "Logs",
+                "https://ci.example.com/jobs/42/log".parse().unwrap(),
+            )]);
+        let encrypted = cipher
+            .encrypt_payload(&payload, "job finished (encrypted)")
+            .unwrap();
+
+        assert_eq!(encrypted.title.as_deref(), Some("job finished (encrypted)"));
+        assert!(!encrypted.message.contains("secret"));
+        assert!(encrypted.click.is_none());
+        assert!(encrypted.actions.is_none());
+        assert!(encrypted.tags.is_none());
+        assert_eq!(encrypted.priority.clone() as u8, 3);
+
+        let envelope = cipher.decrypt_message(&encrypted.message).unwrap();
+        assert_eq!(envelope.title.as_deref(), Some("backup.sh (3s)"));
+        assert_eq!(envelope.message, "secret output");
+        assert!(envelope.markdown);
+        assert_eq!(
+            envelope.click.as_deref(),
+            Some("https://ci.example.com/jobs/42")
+        );
+        assert_eq!(
+            envelope.tags,
+            Some(vec![String::from("x"), String::from("failure")])
+        );
+        assert_eq!(envelope.priority, Some(4));
+
+        let attachment = Attachment {
+            filename: String::from("stdout.txt"),
+            data: b"lots of output".to_vec(),
+        };
+        let sealed = cipher.encrypt_attachment(&attachment).unwrap();
+        assert_eq!(sealed.filename, "stdout.txt.enc");
+        assert_eq!(cipher.decrypt(&sealed.data).unwrap(), attachment.data);
+
+        let other = Cipher::from_base64("ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=").unwrap();
+        assert!(other.decrypt_message(&encrypted.message).is_err());
+
+        assert!(Cipher::from_base64("c2hvcnQ=").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use std::time::Duration;
+
+use chrono::SecondsFormat;
+
+use crate::config::{same_server, Settings};
+use crate::delivery::Delivery;
+use crate::helpers::format_duration;
+use crate::log::GlobalLogger;
+use crate::ntfy::{Payload, Priority};
+use crate::state::{JobState, StateStore};
+
+fn describe_last_seen(state: &JobState) -> String {
+    match (&state.last_check_in, state.last_seen()) {
+        (Some(_), Some(last_seen)) => last_seen.to_rfc3339_opts(SecondsFormat::Secs, false),
+        (None, Some(since)) => format!(
+            "never (registered {})",
+            since.to_rfc3339_opts(SecondsFormat::Secs, false)
+        ),
+        (_, None) => String::from("unknown"),
+    }
+}
+
+fn alert_payload(
+    state: &JobState,
+    settings: &Settings,
+    overdue: Duration,
+) -> Payload {
+    let interval = Duration::from_secs(state.heartbeat.unwrap_or_default());
+
+    Payload::new(&state.topic)
+        .title(format!("Missed heartbeat: {}", state.command))
+        .message(format!(
+            "No successful run since {} (expected every {}, overdue by {})",
+            describe_last_seen(state),
+            format_duration(interval),
+            format_duration(overdue)
+        ))
+        .priority(Priority::from(settings.priority.failure))
+}
+
+/// Send the alert to the endpoint the job notifies (`delivery` is the one of `endpoint`, which older states use).
+async fn send_alert(
+    state: &JobState,
+    settings: &Settings,
+    delivery: &Delivery<'_>,
+    payload: &Payload,
+) -> Result<(), String> {
+    match state.endpoint.as_deref() {
+        Some(endpoint) if !same_server(endpoint, &settings.endpoint) => {
+            Delivery::to_endpoint(settings, endpoint)?
+                .send(payload)
+                .await
+        },
+        _ => delivery.send(payload).await,
+    }
+}
+
+/// Alert the topic of every job with `--heartbeat` that did not check in on time (once per missed check-in).
+/// Returns the number of overdue jobs.
+pub async fn check_heartbeats(
+    store: &StateStore,
+    delivery: &Delivery<'_>,
+    settings: &Settings,
+    grace: Duration,
+) -> usize {
+    let mut overdue_jobs = 0;
+
+    for mut state in store.jobs() {
+        let Some(overdue) = state.overdue(grace) else {
+            continue;
+        };
+
+        overdue_jobs += 1;
+
+        if state.missed {
+            GlobalLogger::info(format!(
+                "'{}' ({}) is still overdue, already alerted",
+                state.command, state.topic
+            ));
+            continue;
+        }
+
+        let payload = alert_payload(&state, settings, overdue);
+
+        match send_alert(&state, settings, delivery, &payload).await {
+            Ok(()) => {
+                state.missed = true;
+                if let Err(error) = store.save(&state) {
+                    GlobalLogger::warn(format!(
+                        "Could not save the state of '{}': {error}",
+                        state.command
+                    ));
+                }
+            },
+            Err(error) => GlobalLogger::error(format!(
+                "Could not send or queue the alert for '{}': {error}",
+                state.command
+            )),
+        }
+    }
+
+    overdue_jobs
+}
+
+/// Overview of all jobs with a heartbeat (`heartbeat list`).
+pub fn list_heartbeats(
+    store: &StateStore,
+    grace: Duration,
+) -> String {
+    let rows: Vec<[String; 5]> = store
+        .jobs()
+        .into_iter()
+        .filter_map(|state| {
+            let interval = Duration::from_secs(state.heartbeat?);
+            let status = state.overdue(grace).map_or_else(
+                || String::from("ok"),
+                |overdue| format!("overdue by {}", format_duration(overdue)),
+            );
+
+            Some([
+                state.topic.clone(),
+                state.command.clone(),
+                format_duration(interval),
+                describe_last_seen(&state),
+                status,
+            ])
+        })
+        .collect();
+
+    if rows.is_empty() {
+        return String::from("No jobs with a heartbeat (see `--heartbeat`)");
+    }
+
+    let header = ["TOPIC", "COMMAND", "EVERY", "LAST CHECK-IN", "STATUS"].map(String::from);
+
+    let mut widths = [0; 5];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    std::iter::once(&header)
+        .chain(&rows)
+        .map(|row| {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .collect();
+            cells.join("  ").trim_end().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use chrono::{Local, SecondsFormat};
+
+    use super::{check_heartbeats, list_heartbeats};
+    use crate::config::Settings;
+    use crate::delivery::Delivery;
+    use crate::helpers::test_server;
+    use crate::state::{JobState, StateStore};
+
+    /// A job that expects a check-in every hour, and last checked in `hours` ago.
+    fn job(
+        command: &str,
+        hours: i64,
+        endpoint: Option<&str>,
+    ) -> JobState {
+        let last_check_in = Local::now() - chrono::Duration::hours(hours);
+
+        JobState {
+            topic: String::from("backups"),
+            command: command.to_string(),
+            heartbeat: Some(3600),
+            last_check_in: Some(last_check_in.to_rfc3339_opts(SecondsFormat::Secs, false)),
+            endpoint: endpoint.map(String::from),
+            ..JobState::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_heartbeats() {
+        let dir =
+            std::env::temp_dir().join(format!("ntfy-log-test-heartbeat-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let (configured, configured_requests) = test_server(200).await;
+        let (job_server, job_requests) = test_server(200).await;
+
+        let mut settings = Settings {
+            endpoint: configured,
+            ..Settings::default()
+        };
+        settings.spool.enabled = false;
+        let delivery = Delivery::new(&settings).unwrap();
+
+        let store = StateStore::new(dir.clone());
+        store.save(&job("on-time.sh", 0, None)).unwrap();
+        store
+            .save(&job("own-server.sh", 3, Some(&job_server)))
+            .unwrap();
+        store.save(&job("old-state.sh", 5, None)).unwrap();
+
+        let grace = Duration::from_mins(5);
+        assert_eq!(
+            check_heartbeats(&store, &delivery, &settings, grace).await,
+            2
+        );
+
+        // each alert goes to the endpoint of its job (the configured one if that isn't known)
+        let requests = job_requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].contains("Missed heartbeat: own-server.sh"));
+
+        let requests = configured_requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].contains("Missed heartbeat: old-state.sh"));
+
+        // still overdue, but alerted only once
+        assert_eq!(
+            check_heartbeats(&store, &delivery, &settings, grace).await,
+            2
+        );
+        assert_eq!(job_requests.lock().unwrap().len(), 1);
+        assert_eq!(configured_requests.lock().unwrap().len(), 1);
+
+        let list = list_heartbeats(&store, grace);
+        let lines: Vec<&str> = list.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("TOPIC    COMMAND"));
+        assert!(lines[1].contains("old-state.sh") && lines[1].contains("overdue by 3h 55m"));
+        assert!(lines[2].contains("on-time.sh") && lines[2].ends_with("ok"));
+        assert!(lines[3].contains("own-server.sh") && lines[3].contains("overdue by 1h 55m"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
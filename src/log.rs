@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 
+use std::io::{self, Write};
 use std::sync::OnceLock;
 
 use clap_verbosity_flag::Level;
@@ -93,6 +94,29 @@ impl Logger {
         }
     }
 
+    /// Write raw bytes to stdout and flush immediately (unless -q), used for streaming output
+    pub fn write_stdout(
+        &self,
+        bytes: &[u8],
+    ) {
+        if self.verbosity.is_some() {
+            let mut handle = io::stdout().lock();
+            // a closed stdout (e.g. `| head`) should not stop the wrapped command
+            let _ = handle.write_all(bytes).and_then(|()| handle.flush());
+        }
+    }
+
+    /// Write raw bytes to stderr and flush immediately (unless -q), used for streaming output
+    pub fn write_stderr(
+        &self,
+        bytes: &[u8],
+    ) {
+        if self.verbosity.is_some() {
+            let mut handle = io::stderr().lock();
+            let _ = handle.write_all(bytes).and_then(|()| handle.flush());
+        }
+    }
+
     pub fn error<S: Into<String>>(
         &self,
         text: S,
@@ -209,6 +233,14 @@ impl GlobalLogger {
     pub fn stderr<S: Into<String>>(text: S) {
         Self::singleton().stderr(text);
     }
+
+    pub fn write_stdout(bytes: &[u8]) {
+        Self::singleton().write_stdout(bytes);
+    }
+
+    pub fn write_stderr(bytes: &[u8]) {
+        Self::singleton().write_stderr(bytes);
+    }
 }
 
 #[cfg(test)]
@@ -257,5 +289,13 @@ mod tests {
         local_logger.stderr("stderr 1");
         global_logger.stderr("stderr 2");
         GlobalLogger::stderr("stderr 3");
+
+        local_logger.write_stdout(b"raw stdout 1\n");
+        global_logger.write_stdout(b"raw stdout 2\n");
+        GlobalLogger::write_stdout(b"raw stdout 3\n");
+
+        local_logger.write_stderr(b"raw stderr 1\n");
+        global_logger.write_stderr(b"raw stderr 2\n");
+        GlobalLogger::write_stderr(b"raw stderr 3\n");
     }
 }
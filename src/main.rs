@@ -1,23 +1,61 @@
 mod cli;
 mod command;
+mod config;
 mod constants;
+mod delivery;
+mod encryption;
+mod format;
+mod heartbeat;
 mod helpers;
 mod http;
 mod log;
+mod notifier;
 mod ntfy;
+mod outcome;
+mod progress;
+mod pty;
+mod redact;
 mod self_update;
+mod signal;
+mod smtp;
+mod spool;
+mod state;
+mod stdin;
+mod tail;
+mod target;
+mod usage;
 
-use clap::{CommandFactory, Parser};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
 use clap_verbosity_flag::Level;
-use helpers::ResultToString;
+use futures_util::future::join_all;
+use owo_colors::OwoColorize;
+use tokio::sync::mpsc;
 
 use crate::log::GlobalLogger;
 
-use self::cli::Cli;
-use self::command::run_cmd;
+use self::cli::{Cli, Commands, ConfigAction, HeartbeatAction};
+use self::command::{command_line, run_cmd, CommandResult, InvalidArgsNoStdIn, RunOptions};
+use self::config::Config;
+use self::constants::ENCRYPTED_PREFIX;
+use self::delivery::Delivery;
+use self::encryption::Cipher;
+use self::heartbeat::{check_heartbeats, list_heartbeats};
+use self::helpers::ResultToString;
 use self::log::Logger;
-use self::ntfy::{setup_ntfy, Payload};
+use self::notifier::is_ntfy;
+use self::ntfy::{Payload, Priority};
+use self::outcome::OutcomeRules;
+use self::progress::LiveUpdates;
+use self::redact::Redactor;
 use self::self_update::{current_version, pkg_name, self_update};
+use self::state::{Decision, NotifyMode};
+use self::stdin::{read_stdin, stdin_is_piped, PipeMode};
+use self::tail::Tail;
+use self::target::{resolve_targets, Destination};
 
 fn print_version(logger: &Logger) -> i32 {
     println!("{} {}", pkg_name(), current_version());
@@ -32,9 +70,402 @@ fn print_version(logger: &Logger) -> i32 {
     0
 }
 
-/// Main logic, but returns a Result(exit code | ntfy error) instead of exiting.
+fn print_help() -> i32 {
+    Cli::command()
+        // .color(clap::ColorChoice::Always) // coloring does not work here for some reason (but it does for default help?)
+        .print_help()
+        .unwrap_or_default();
+    2 // exit code 2
+}
+
+/// Send the result to the destination's topic. Returns the payload as it was sent.
+async fn notify(
+    destination: &Destination<'_>,
+    config: &Config,
+    result: &CommandResult,
+    redactor: &Redactor,
+    title_prefix: Option<&str>,
+) -> Result<Payload, String> {
+    let settings = &config.settings;
+    let Destination { target, delivery } = destination;
+    let topic = target.topic.as_str();
+    let payload = result.build_payload(topic, settings, redactor, title_prefix);
+
+    // replaces the "Running:" message, where the server supports updating messages
+    delivery
+        .send_result(
+            &payload,
+            &result.details(settings),
+            result.sequence_id.as_deref(),
+        )
+        .await?;
+
+    // output that didn't fit in the message:
+    let title = payload.title.clone().unwrap_or_default();
+    for attachment in result.attachments(&settings.attachments) {
+        let attachment_payload = Payload::new(topic)
+            .title(format!("{title} ({})", attachment.filename))
+            .priority(Priority::Low);
+
+        delivery
+            .send_attachment(&attachment_payload, &attachment)
+            .await?;
+    }
+
+    Ok(payload)
+}
+
+/// Send the title (or more, see `secondary.content`) of the delivered payloads to the success, failure or timeout
+/// topic. Once per run with `secondary.endpoint` (for the job's `topic`), otherwise once per server and topic.
+async fn notify_secondary(
+    delivered: &[(&Destination<'_>, Payload)],
+    config: &Config,
+    result: &CommandResult,
+    topic: &str,
+) -> Result<(), String> {
+    let settings = &config.settings;
+    let mut secondaries: Vec<(&str, &str, Payload)> = Vec::new();
+
+    for (destination, payload) in delivered {
+        let primary = destination.target.endpoint.as_str();
+        let endpoint = settings.secondary_endpoint(primary);
+
+        // other services ignore the topic, so it would be the same message again
+        if !is_ntfy(endpoint) {
+            continue;
+        }
+
+        let primary_topic = match settings.secondary.endpoint {
+            Some(_) => topic,
+            None => destination.target.topic.as_str(),
+        };
+        let secondary_payload = result.build_secondary_payload(payload, primary_topic, settings);
+
+        let duplicate = secondaries.iter().any(|(other_endpoint, _, other)| {
+            *other_endpoint == endpoint && other.topic == secondary_payload.topic
+        });
+        if !duplicate {
+            secondaries.push((endpoint, primary, secondary_payload));
+        }
+    }
+
+    for (_, primary, payload) in &secondaries {
+        Delivery::secondary(settings, primary)?
+            .send(payload)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Notify every destination at once, and report how that went for each (with `--to`).
+/// Then send the secondary message (for the job's `topic`). Returns false if anything failed.
+async fn notify_all(
+    destinations: &[Destination<'_>],
+    config: &Config,
+    result: &CommandResult,
+    redactor: &Redactor,
+    title_prefix: Option<&str>,
+    topic: &str,
+    logger: &Logger,
+) -> bool {
+    let results = join_all(
+        destinations
+            .iter()
+            .map(|destination| notify(destination, config, result, redactor, title_prefix)),
+    )
+    .await;
+
+    let fan_out = destinations.len() > 1;
+    let mut success = true;
+    let mut delivered = Vec::new();
+
+    for (destination, sent) in destinations.iter().zip(results) {
+        match sent {
+            Ok(payload) => {
+                if fan_out {
+                    logger.success(format!("Sent to {}", destination.target));
+                }
+                delivered.push((destination, payload));
+            },
+            Err(error) if fan_out => {
+                logger.error(format!(
+                    "Could not send or queue notification to {}: {error}",
+                    destination.target
+                ));
+                success = false;
+            },
+            Err(error) => {
+                logger.error(format!("Could not send or queue notification: {error}"));
+                success = false;
+            },
+        }
+    }
+
+    if config.settings.secondary.enabled {
+        if let Err(error) = notify_secondary(&delivered, config, result, topic).await {
+            logger.error(format!(
+                "Could not send or queue the secondary notification: {error}"
+            ));
+            success = false;
+        }
+    }
+
+    success
+}
+
+/// Run the command, with a message when it starts (`--notify-start`) and progress updates (`--progress-every`).
+/// Those updates are returned as well if there were any, the final notification replaces them.
+async fn run_with_updates<'a>(
+    args: &Cli,
+    options: &RunOptions,
+    destinations: &'a [Destination<'a>],
+    redactor: &'a Redactor,
+) -> Result<(CommandResult, Option<LiveUpdates<'a>>), InvalidArgsNoStdIn> {
+    let command = command_line(&args.subcommand, &options.launcher);
+    let live = LiveUpdates::new(destinations, redactor, &command);
+
+    if args.notify_start {
+        live.started().await;
+    }
+
+    // without `--progress-every`, the sender is dropped right away and this just runs the command
+    let (sender, mut receiver) = mpsc::channel(1);
+
+    let send_updates = async {
+        while let Some(progress) = receiver.recv().await {
+            live.progress(&progress).await;
+        }
+    };
+
+    let (result, ()) = tokio::join!(
+        run_cmd(&args.subcommand, options, Some(sender)),
+        send_updates
+    );
+
+    let mut result = result?;
+    if !args.notify_start && args.progress_every.is_none() {
+        return Ok((result, None));
+    }
+
+    result.sequence_id = Some(live.sequence_id().to_string());
+    Ok((result, Some(live)))
+}
+
+/// Whether to notify according to `--notify`. For anything but `always`, the outcome is remembered for the next run.
+fn notify_decision(
+    args: &Cli,
+    config: &Config,
+    destinations: &[Destination<'_>],
+    result: &CommandResult,
+) -> Decision {
+    let settings = &config.settings.notify;
+    // a missed heartbeat is reported where the job's (first) notification goes
+    let endpoint = destinations
+        .first()
+        .map_or(&config.settings.endpoint, |destination| {
+            &destination.target.endpoint
+        });
+
+    match (settings.mode, args.heartbeat, config.settings.state_store()) {
+        (NotifyMode::Always, None, _) | (_, _, None) => Decision::Notify,
+        (_, heartbeat, Some(store)) => {
+            store.check(endpoint, args.get_topic(), result, settings, heartbeat)
+        },
+    }
+}
+
+/// `heartbeat check` (exit code 1 if any job is overdue) or `heartbeat list`.
+async fn heartbeat(
+    action: &HeartbeatAction,
+    config: &Config,
+    delivery: &Delivery<'_>,
+    logger: &Logger,
+) -> Result<i32, String> {
+    let store = config
+        .settings
+        .state_store()
+        .ok_or("Could not determine the state directory")?;
+
+    match action {
+        HeartbeatAction::List { grace } => {
+            println!("{}", list_heartbeats(&store, *grace));
+            Ok(0)
+        },
+        HeartbeatAction::Check { grace } => {
+            flush_spool(delivery, logger, false).await;
+
+            let overdue = check_heartbeats(&store, delivery, &config.settings, *grace).await;
+            if overdue > 0 {
+                logger.warn(format!("{overdue} job(s) missed their heartbeat"));
+                return Ok(1);
+            }
+
+            Ok(0)
+        },
+    }
+}
+
+/// Send everything that is piped into `ntfy-log` (as one or more messages, depending on `--pipe`).
+async fn notify_stdin(
+    args: &Cli,
+    config: &Config,
+    logger: &Logger,
+    destinations: &[Destination<'_>],
+    outcome_rules: &OutcomeRules,
+    redactor: &Redactor,
+) -> i32 {
+    let options = args.pipe_options();
+
+    if options.mode == PipeMode::All {
+        logger.important("warn".yellow().to_string(),
+                         "Since complex bash commands (including pipes) is now supported by ntfy-push, using stdin is highly discouraged.");
+    }
+
+    let mut messages = read_stdin(options);
+    let mut exit_code = 0;
+
+    while let Some(message) = messages.recv().await {
+        let mut result = CommandResult::from_stdin(message);
+        outcome_rules.apply(&mut result);
+        redactor.apply(&mut result);
+
+        let topic = args.get_topic();
+        if !notify_all(destinations, config, &result, redactor, None, topic, logger).await {
+            exit_code = 1;
+        }
+    }
+
+    exit_code
+}
+
+/// Print an encrypted message (or explain why it can't be decrypted).
+fn print_decrypted(
+    cipher: &Cipher,
+    message: &str,
+    logger: &Logger,
+) -> bool {
+    match cipher.decrypt_message(message) {
+        Ok(envelope) => {
+            if let Some(title) = envelope.title {
+                println!("{}", title.bold());
+            }
+            if let Some(tags) = envelope.tags {
+                println!("tags: {}", tags.join(", "));
+            }
+            if let Some(priority) = envelope.priority.filter(|priority| *priority != 3) {
+                println!("priority: {priority}");
+            }
+            println!("{}", envelope.message);
+            if let Some(click) = envelope.click {
+                println!("{click}");
+            }
+            println!();
+            true
+        },
+        Err(error) => {
+            logger.error(error);
+            false
+        },
+    }
+}
+
+/// `decrypt`: messages from stdin (one per line, as sent or as ntfy's JSON stream), or the given files.
+/// Exit code 1 if anything could not be decrypted.
+fn decrypt(
+    files: &[PathBuf],
+    config: &Config,
+    logger: &Logger,
+) -> Result<i32, String> {
+    let cipher = Cipher::from_settings(&config.settings.encryption)?
+        .ok_or("No encryption key configured, set `encryption.key` or `--encryption-key-file`")?;
+
+    let mut failed = false;
+
+    for path in files {
+        let data = fs::read(path)
+            .map_err(|error| format!("Could not read {}: {error}", path.display()))?;
+
+        // a long message may have been turned into an attachment by the server
+        if data.starts_with(ENCRYPTED_PREFIX.as_bytes()) {
+            failed |= !print_decrypted(&cipher, &String::from_utf8_lossy(&data), logger);
+            continue;
+        }
+
+        match cipher.decrypt(&data) {
+            Ok(decrypted) => std::io::stdout()
+                .write_all(&decrypted)
+                .map_err_to_string()?,
+            Err(error) => {
+                logger.error(format!("{}: {error}", path.display()));
+                failed = true;
+            },
+        }
+    }
+
+    if !files.is_empty() {
+        return Ok(i32::from(failed));
+    }
+
+    for line in std::io::stdin().lines() {
+        let line = line.map_err_to_string()?;
+
+        let message = match serde_json::from_str::<serde_json::Value>(&line) {
+            // ntfy's JSON stream also contains `open` and `keepalive` events
+            Ok(event) if event.is_object() => match event["message"].as_str() {
+                Some(message) if event["event"] == "message" => message.to_string(),
+                _ => continue,
+            },
+            _ => line,
+        };
+
+        if message.trim().is_empty() {
+            continue;
+        }
+
+        failed |= !print_decrypted(&cipher, &message, logger);
+    }
+
+    Ok(i32::from(failed))
+}
+
+/// Deliver queued notifications, before new ones are sent.
+async fn flush_spool(
+    delivery: &Delivery<'_>,
+    logger: &Logger,
+    force: bool,
+) -> i32 {
+    let result = delivery.flush(force).await;
+
+    if result.delivered > 0 {
+        logger.success(format!(
+            "Delivered {} queued notification(s)",
+            result.delivered
+        ));
+    }
+
+    if result.rejected > 0 {
+        logger.warn(format!(
+            "Gave up on {} queued notification(s)",
+            result.rejected
+        ));
+    }
+
+    if result.remaining > 0 {
+        logger.warn(format!(
+            "{} notification(s) are still queued",
+            result.remaining
+        ));
+    }
+
+    i32::from(result.remaining > 0 || result.rejected > 0)
+}
+
+/// Main logic, but returns a Result(exit code | setup error) instead of exiting.
+/// The exit code of the command is always returned, even if its notification could not be delivered.
 async fn main_with_exitcode(
     args: &Cli,
+    matches: &ArgMatches,
     logger: &Logger,
 ) -> Result<i32, String> {
     if args.version {
@@ -43,51 +474,113 @@ async fn main_with_exitcode(
         return self_update(logger).await;
     }
 
-    let topic = args.get_topic();
+    let config = Config::load(args, matches)?;
+
+    // these don't send anything
+    match &args.command {
+        Some(Commands::Config {
+            action: ConfigAction::Show,
+        }) => {
+            println!("{}", config.show());
+            return Ok(0);
+        },
+        Some(Commands::Decrypt { files }) => return decrypt(files, &config, logger),
+        _ => {},
+    }
 
-    let ntfy = setup_ntfy(&args.endpoint);
+    let delivery = Delivery::new(&config.settings)?;
 
-    let exit_code = match run_cmd(&args.subcommand).await {
-        Err(_) => {
-            Cli::command()
-                // .color(clap::ColorChoice::Always) // coloring does not work here for some reason (but it does for default help?)
-                .print_help()
-                .unwrap_or_default();
-            2 // exit code 2
+    match &args.command {
+        Some(Commands::Flush) => return Ok(flush_spool(&delivery, logger, true).await),
+        Some(Commands::Heartbeat { action }) => {
+            return heartbeat(action, &config, &delivery, logger).await;
         },
+        Some(Commands::Tail(tail_args)) => {
+            let settings = tail_args.settings(&config.settings.tail);
+            let rules = tail_args.rules(&settings);
+            let redactor = Redactor::new(&config.settings.redact)?;
 
-        Ok(result) => {
-            let mut payload = result.build_payload(topic);
+            let mut tail = Tail::new(
+                &tail_args.topic,
+                &tail_args.files,
+                &rules,
+                &settings,
+                &delivery,
+                &redactor,
+                tail_args.from_start,
+            )?;
 
-            if !args.title.is_empty() {
-                payload = payload.title(&args.title);
-            }
+            flush_spool(&delivery, logger, false).await;
+            tail.run().await?;
+            return Ok(0);
+        },
+        Some(Commands::Config { .. } | Commands::Decrypt { .. }) | None => {},
+    }
 
-            logger.info(format!("Sending {:?} to {}", payload, args.endpoint));
+    // invalid patterns should be reported before the command runs
+    let outcome_rules = OutcomeRules::new(&config.settings.outcome)?;
+    let redactor = Redactor::new(&config.settings.redact)?;
+    let targets = resolve_targets(&args.to, args.get_topic(), &config.settings)?;
+    let destinations = Destination::all(&targets, &config.settings)?;
 
-            ntfy.send(&payload).await.map_err_to_string()?;
+    if args.subcommand.is_empty() {
+        // no subcommand arg(s), hopefully something was piped.
+        if !stdin_is_piped() {
+            return Ok(print_help());
+        }
 
-            // also send 'title' to the success or failure channel:
-            // todo: make this an option
+        flush_spool(&delivery, logger, false).await;
+        return Ok(notify_stdin(
+            args,
+            &config,
+            logger,
+            &destinations,
+            &outcome_rules,
+            &redactor,
+        )
+        .await);
+    }
 
-            let suffix = if result.success() {
-                "success"
-            } else {
-                "failure"
-            };
+    let options = args.run_options()?;
+    if options.pty && config.settings.outcome.uses_stderr() {
+        logger.warn(
+            "With --pty, stderr is part of stdout, so the stderr rules in [outcome] never apply",
+        );
+    }
 
-            let secondary_topic = format!("{topic}--{suffix}");
+    let exit_code = match run_with_updates(args, &options, &destinations, &redactor).await {
+        Err(_) => print_help(),
 
-            let secondary_msg = payload.title.unwrap_or_default();
+        Ok((mut result, live)) => {
+            // the rules see the actual output, everything after that only the redacted one
+            outcome_rules.apply(&mut result);
+            redactor.apply(&mut result);
+            flush_spool(&delivery, logger, false).await;
 
-            let secondary_payload = Payload::new(secondary_topic).message(&secondary_msg);
+            match notify_decision(args, &config, &destinations, &result) {
+                Decision::Suppress(reason) => {
+                    logger.info(format!("Not sending a notification: {reason}"));
 
-            logger.info(format!(
-                "Sending {:?} to {}.",
-                secondary_payload, args.endpoint
-            ));
+                    // otherwise "Running: ..." would stay on the topic
+                    if let Some(live) = live {
+                        live.finished(&result).await;
+                    }
+                },
+                decision => {
+                    let title_prefix = (decision == Decision::Flapping).then_some("Flapping: ");
 
-            ntfy.send(&secondary_payload).await.map_err_to_string()?;
+                    notify_all(
+                        &destinations,
+                        &config,
+                        &result,
+                        &redactor,
+                        title_prefix,
+                        args.get_topic(),
+                        logger,
+                    )
+                    .await;
+                },
+            }
 
             result.exit_code
         },
@@ -100,10 +593,11 @@ async fn main_with_exitcode(
 #[tokio::main]
 async fn main() -> ! {
     // color_eyre::install()?;
-    let args = Cli::parse();
+    let matches = Cli::matches_from(std::env::args_os()).unwrap_or_else(|error| error.exit());
+    let args = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
     let logger = GlobalLogger::setup(&args.verbose);
 
-    match main_with_exitcode(&args, logger).await {
+    match main_with_exitcode(&args, &matches, logger).await {
         Ok(code) => std::process::exit(code),
         Err(error) => {
             logger.error(error);
@@ -0,0 +1,703 @@
+use std::fmt;
+use std::time::Duration;
+
+use chrono::Utc;
+use reqwest::header::AUTHORIZATION;
+use reqwest::{RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::constants::{CHAT_MAX_BYTES, HTTP_TIMEOUT};
+use crate::helpers::{format_duration, tail, ResultToString};
+use crate::log::GlobalLogger;
+use crate::ntfy::{setup_ntfy, Attachment, Credentials, Ntfy, Payload};
+use crate::outcome::Outcome;
+use crate::smtp::Smtp;
+
+/// Why a notification could not be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendError {
+    /// The service could not be reached or had a problem (5xx), sending it again later may work.
+    Temporary(String),
+    /// The service refused the notification itself (4xx), it would be refused again.
+    Rejected(String),
+}
+
+impl SendError {
+    /// The error for an unsuccessful response. Timeouts and rate limiting (408, 429) are temporary.
+    pub fn status(
+        context: &str,
+        status: StatusCode,
+    ) -> Self {
+        let message = format!("{context}: {status}");
+
+        match status {
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => Self::Temporary(message),
+            status if status.is_client_error() => Self::Rejected(message),
+            _ => Self::Temporary(message),
+        }
+    }
+
+    pub const fn is_temporary(&self) -> bool {
+        matches!(self, Self::Temporary(_))
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            Self::Temporary(message) | Self::Rejected(message) => f.write_str(message),
+        }
+    }
+}
+
+/// Connection errors and the like, which are all worth another try.
+impl From<String> for SendError {
+    fn from(message: String) -> Self {
+        Self::Temporary(message)
+    }
+}
+
+impl From<SendError> for String {
+    fn from(error: SendError) -> Self {
+        error.to_string()
+    }
+}
+
+/// Client for the http services, which gives up on a server that doesn't answer.
+pub fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .build()
+        .expect("the TLS backend can be initialized")
+}
+
+/// The result of a command as separate fields, for the services that format it themselves (the ntfy message is
+/// JSON by default). Not sent for other notifications (heartbeats, `--follow`), nor when they are encrypted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Details {
+    pub command: String,
+    pub outcome: Outcome,
+    /// e.g. `failed with exit code 3`, see `CommandResult::status_text`.
+    pub status: String,
+    /// The exit code and duration are not known when reading from stdin.
+    pub exit_code: Option<i32>,
+    pub signal: Option<String>,
+    pub duration_ms: Option<u64>,
+    /// `user@hostname`
+    pub host: String,
+    /// Only the last lines, if the output is attached.
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Details {
+    /// e.g. `failed with exit code 3 after 12m 03s on user@host`.
+    pub fn summary(&self) -> String {
+        self.duration_ms.map_or_else(
+            || format!("{} {} on {}", self.command, self.status, self.host),
+            |duration_ms| {
+                format!(
+                    "{} after {} on {}",
+                    self.status,
+                    format_duration(Duration::from_millis(duration_ms)),
+                    self.host
+                )
+            },
+        )
+    }
+
+    /// stdout followed by stderr.
+    pub fn output(&self) -> String {
+        [self.stdout.trim_end(), self.stderr.trim_end()]
+            .into_iter()
+            .filter(|output| !output.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The summary and the output as plain text.
+    pub fn text(&self) -> String {
+        let output = self.output();
+
+        if output.is_empty() {
+            self.summary()
+        } else {
+            format!("{}\n\n{output}", self.summary())
+        }
+    }
+}
+
+/// A service that notifications are delivered to.
+pub trait Notifier {
+    /// Send the payload, with its attachment if the service supports attachments.
+    /// Services that format the message themselves use the `details` of the result, if there are any.
+    async fn publish(
+        &self,
+        payload: &Payload,
+        details: Option<&Details>,
+        attachment: Option<&Attachment>,
+    ) -> Result<(), SendError>;
+
+    /// Replace an earlier message with the same sequence id. Services that can't do that get a new message.
+    async fn update(
+        &self,
+        payload: &Payload,
+        details: Option<&Details>,
+        _sequence_id: &str,
+    ) -> Result<(), SendError> {
+        self.publish(payload, details, None).await
+    }
+}
+
+/// The title with the summary and the end of the output as text for chat services,
+/// or the end of the message if there are no details.
+fn chat_text(
+    payload: &Payload,
+    details: Option<&Details>,
+    bold: &str,
+    max_bytes: usize,
+) -> String {
+    let title = payload.title.as_deref().unwrap_or_default();
+
+    let Some(details) = details else {
+        let message = tail(&payload.message, usize::MAX, max_bytes);
+
+        return match (message.is_empty(), payload.markdown) {
+            (true, _) => format!("{bold}{title}{bold}"),
+            (false, true) => format!("{bold}{title}{bold}\n{message}"),
+            (false, false) => format!("{bold}{title}{bold}\n```\n{message}\n```"),
+        };
+    };
+
+    let output = details.output();
+    let output = tail(&output, usize::MAX, max_bytes);
+    let summary = details.summary();
+
+    if output.is_empty() {
+        format!("{bold}{title}{bold}\n{summary}")
+    } else {
+        format!("{bold}{title}{bold}\n{summary}\n```\n{output}\n```")
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn skip_attachment(
+    service: &str,
+    attachment: Option<&Attachment>,
+) {
+    if let Some(attachment) = attachment {
+        GlobalLogger::info(format!(
+            "{service} does not support attachments, skipping {}",
+            attachment.filename
+        ));
+    }
+}
+
+async fn send_json(
+    service: &str,
+    request: RequestBuilder,
+    body: &Value,
+) -> Result<(), SendError> {
+    let response = request.json(body).send().await.map_err_to_string()?;
+
+    if !response.status().is_success() {
+        return Err(SendError::status(service, response.status()));
+    }
+
+    Ok(())
+}
+
+/// `webhook://host/path`: the payload as JSON, in a `POST` request to `https://host/path`.
+pub struct Webhook {
+    url: Url,
+    client: reqwest::Client,
+    credentials: Option<Credentials>,
+}
+
+impl Notifier for Webhook {
+    async fn publish(
+        &self,
+        payload: &Payload,
+        details: Option<&Details>,
+        attachment: Option<&Attachment>,
+    ) -> Result<(), SendError> {
+        skip_attachment("webhook", attachment);
+
+        let mut body = json!({
+            "topic": payload.topic,
+            "title": payload.title,
+            "message": payload.message,
+            "priority": payload.priority.clone() as u8,
+            "tags": payload.tags,
+            "click": payload.click,
+            "markdown": payload.markdown,
+        });
+        if let Some(details) = details {
+            body["result"] = serde_json::to_value(details).map_err_to_string()?;
+        }
+
+        let mut request = self.client.post(self.url.clone());
+        request = match &self.credentials {
+            Some(Credentials::Token(token)) => request.bearer_auth(token),
+            Some(Credentials::Basic { user, password }) => request.basic_auth(user, Some(password)),
+            None => request,
+        };
+
+        send_json("webhook", request, &body).await
+    }
+}
+
+/// `gotify://host/<app token>` (or the token from `--token`).
+pub struct Gotify {
+    url: Url,
+    token: String,
+    client: reqwest::Client,
+}
+
+impl Notifier for Gotify {
+    async fn publish(
+        &self,
+        payload: &Payload,
+        details: Option<&Details>,
+        attachment: Option<&Attachment>,
+    ) -> Result<(), SendError> {
+        skip_attachment("Gotify", attachment);
+
+        // ntfy's 1-5 to Gotify's 0-10 (where 8 and up is high)
+        let priority = match payload.priority.clone() as u8 {
+            1 => 1,
+            2 => 3,
+            3 => 5,
+            4 => 8,
+            _ => 10,
+        };
+
+        let (message, content_type) = match details {
+            Some(details) => (details.text(), "text/plain"),
+            None if payload.markdown => (payload.message.clone(), "text/markdown"),
+            None => (payload.message.clone(), "text/plain"),
+        };
+
+        let mut extras = json!({ "client::display": { "contentType": content_type } });
+        if let Some(click) = &payload.click {
+            extras["client::notification"] = json!({ "click": { "url": click } });
+        }
+
+        let body = json!({
+            "title": payload.title,
+            "message": message,
+            "priority": priority,
+            "extras": extras,
+        });
+
+        let request = self
+            .client
+            .post(self.url.clone())
+            .header("X-Gotify-Key", &self.token);
+
+        send_json("Gotify", request, &body).await
+    }
+}
+
+/// `slack://hooks.slack.com/services/...` or `mattermost://host/hooks/...` (incoming webhooks).
+pub struct Slack {
+    url: Url,
+    client: reqwest::Client,
+    /// Slack uses `*bold*`, Mattermost `**bold**`.
+    bold: &'static str,
+    icon: Option<String>,
+}
+
+impl Notifier for Slack {
+    async fn publish(
+        &self,
+        payload: &Payload,
+        details: Option<&Details>,
+        attachment: Option<&Attachment>,
+    ) -> Result<(), SendError> {
+        skip_attachment("Slack", attachment);
+
+        let mut body = json!({ "text": chat_text(payload, details, self.bold, CHAT_MAX_BYTES) });
+        if let Some(icon) = &self.icon {
+            body["icon_url"] = icon.as_str().into();
+        }
+
+        send_json("Slack", self.client.post(self.url.clone()), &body).await
+    }
+}
+
+/// `discord://discord.com/api/webhooks/<id>/<token>`.
+pub struct Discord {
+    url: Url,
+    client: reqwest::Client,
+    icon: Option<String>,
+}
+
+/// Discord messages can't be longer than 2000 characters.
+const DISCORD_MAX_BYTES: usize = 1800;
+
+impl Notifier for Discord {
+    async fn publish(
+        &self,
+        payload: &Payload,
+        details: Option<&Details>,
+        attachment: Option<&Attachment>,
+    ) -> Result<(), SendError> {
+        skip_attachment("Discord", attachment);
+
+        let mut body = json!({ "content": chat_text(payload, details, "**", DISCORD_MAX_BYTES) });
+        if let Some(icon) = &self.icon {
+            body["avatar_url"] = icon.as_str().into();
+        }
+
+        send_json("Discord", self.client.post(self.url.clone()), &body).await
+    }
+}
+
+/// `matrix://<access token>@homeserver/<room id>` (or the token from `--token`).
+pub struct Matrix {
+    /// `.../rooms/<room id>/send/m.room.message/`, the transaction id is added per message.
+    url: Url,
+    token: String,
+    client: reqwest::Client,
+}
+
+impl Notifier for Matrix {
+    async fn publish(
+        &self,
+        payload: &Payload,
+        details: Option<&Details>,
+        attachment: Option<&Attachment>,
+    ) -> Result<(), SendError> {
+        skip_attachment("Matrix", attachment);
+
+        let title = payload.title.as_deref().unwrap_or_default();
+        let (summary, output) = details.map_or_else(
+            || (String::new(), payload.message.clone()),
+            |details| (details.summary(), details.output()),
+        );
+        let output = tail(&output, usize::MAX, CHAT_MAX_BYTES);
+
+        let (text, html) = if summary.is_empty() {
+            (
+                String::from(title),
+                format!("<b>{}</b>", html_escape(title)),
+            )
+        } else {
+            (
+                format!("{title}\n{summary}"),
+                format!("<b>{}</b><br>{}", html_escape(title), html_escape(&summary)),
+            )
+        };
+
+        let body = json!({
+            "msgtype": "m.text",
+            "body": format!("{text}\n\n{output}"),
+            "format": "org.matrix.custom.html",
+            "formatted_body": format!("{html}<pre><code>{}</code></pre>", html_escape(output)),
+        });
+
+        let transaction = format!(
+            "ntfy-log-{}-{}",
+            std::process::id(),
+            Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        );
+        let url = self.url.join(&transaction).map_err_to_string()?;
+
+        let request = self
+            .client
+            .put(url)
+            .header(AUTHORIZATION, format!("Bearer {}", self.token));
+
+        send_json("Matrix", request, &body).await
+    }
+}
+
+/// Everything notifications can be sent to, selected by the scheme of the endpoint.
+pub enum Backend {
+    Ntfy(Ntfy),
+    Webhook(Webhook),
+    Gotify(Gotify),
+    Slack(Slack),
+    Discord(Discord),
+    Matrix(Matrix),
+    Smtp(Smtp),
+}
+
+/// Whether the endpoint is an ntfy server (rather than one of the other services).
+pub fn is_ntfy(endpoint: &str) -> bool {
+    endpoint.split_once("://").is_none_or(|(scheme, _)| {
+        matches!(
+            scheme.to_lowercase().trim_end_matches("+http"),
+            "http" | "https" | "ntfy"
+        )
+    })
+}
+
+/// `service://host/path` as `https://host/path`, or `http://host/path` for `service+http://`.
+fn http_url(
+    scheme: &str,
+    rest: &str,
+) -> Result<Url, String> {
+    let protocol = if scheme.ends_with("+http") {
+        "http"
+    } else {
+        "https"
+    };
+
+    Url::parse(&format!("{protocol}://{rest}"))
+        .map_err(|error| format!("Invalid {scheme} endpoint: {error}"))
+}
+
+/// The username of the endpoint, or the token from `--token`.
+fn token(
+    scheme: &str,
+    url: &mut Url,
+    credentials: Option<&Credentials>,
+) -> Result<String, String> {
+    let token = match (url.username(), credentials) {
+        ("", Some(Credentials::Token(token))) => token.clone(),
+        ("", _) => return Err(format!("The {scheme} endpoint needs a token")),
+        (token, _) => token.to_string(),
+    };
+
+    let _ = url.set_username("");
+    Ok(token)
+}
+
+impl Backend {
+    /// e.g. `ntfy.sh` or `https://ntfy.example.com` (ntfy), `gotify://gotify.example.com/<token>`,
+    /// `slack://hooks.slack.com/services/...`, `matrix://<token>@matrix.org/!room:matrix.org` or `smtps://...`.
+    pub fn from_endpoint(
+        endpoint: &str,
+        credentials: Option<&Credentials>,
+        icon: Option<String>,
+    ) -> Result<Self, String> {
+        let Some((scheme, rest)) = endpoint.split_once("://") else {
+            return Ok(Self::Ntfy(
+                setup_ntfy(endpoint, credentials).with_icon(icon),
+            ));
+        };
+
+        let scheme = scheme.to_lowercase();
+        let client = http_client();
+
+        let backend = match scheme.trim_end_matches("+http") {
+            "http" | "https" => Self::Ntfy(setup_ntfy(endpoint, credentials).with_icon(icon)),
+            "ntfy" => Self::Ntfy(
+                setup_ntfy(http_url(&scheme, rest)?.as_str(), credentials).with_icon(icon),
+            ),
+            "webhook" => Self::Webhook(Webhook {
+                url: http_url(&scheme, rest)?,
+                client,
+                credentials: credentials.cloned(),
+            }),
+            "gotify" => {
+                let mut url = http_url(&scheme, rest)?;
+                let mut segments: Vec<String> = url
+                    .path_segments()
+                    .map(|segments| segments.filter(|s| !s.is_empty()).map(String::from).collect())
+                    .unwrap_or_default();
+
+                let token = match credentials {
+                    Some(Credentials::Token(token)) if url.path() == "/" => token.clone(),
+                    _ => segments.pop().ok_or("The gotify endpoint needs an app token")?,
+                };
+
+                segments.push(String::from("message"));
+                url.set_path(&segments.join("/"));
+
+                Self::Gotify(Gotify { url, token, client })
+            },
+            "slack" | "mattermost" => Self::Slack(Slack {
+                url: http_url(&scheme, rest)?,
+                client,
+                bold: if scheme.starts_with("slack") { "*" } else { "**" },
+                icon,
+            }),
+            "discord" => Self::Discord(Discord {
+                url: http_url(&scheme, rest)?,
+                client,
+                icon,
+            }),
+            "matrix" => {
+                let mut url = http_url(&scheme, rest)?;
+                let token = token(&scheme, &mut url, credentials)?;
+
+                let room = url.path().trim_matches('/').to_string();
+                if room.is_empty() {
+                    return Err(String::from("The matrix endpoint needs a room id"));
+                }
+
+                url.set_path("/_matrix/client/v3/rooms/");
+                url.path_segments_mut()
+                    .map_err(|()| String::from("Invalid matrix endpoint"))?
+                    .pop_if_empty()
+                    .extend([room.as_str(), "send", "m.room.message", ""]);
+
+                Self::Matrix(Matrix { url, token, client })
+            },
+            "smtp" | "smtps" => Self::Smtp(Smtp::from_endpoint(endpoint, credentials)?),
+            _ => {
+                return Err(format!(
+                    "Unknown endpoint scheme `{scheme}://`, expected http(s), ntfy, webhook, gotify, slack, \
+                     mattermost, discord, matrix, smtp or smtps"
+                ))
+            },
+        };
+
+        Ok(backend)
+    }
+}
+
+impl Notifier for Backend {
+    async fn publish(
+        &self,
+        payload: &Payload,
+        details: Option<&Details>,
+        attachment: Option<&Attachment>,
+    ) -> Result<(), SendError> {
+        match self {
+            Self::Ntfy(ntfy) => ntfy.publish(payload, details, attachment).await,
+            Self::Webhook(webhook) => webhook.publish(payload, details, attachment).await,
+            Self::Gotify(gotify) => gotify.publish(payload, details, attachment).await,
+            Self::Slack(slack) => slack.publish(payload, details, attachment).await,
+            Self::Discord(discord) => discord.publish(payload, details, attachment).await,
+            Self::Matrix(matrix) => matrix.publish(payload, details, attachment).await,
+            Self::Smtp(smtp) => smtp.publish(payload, details, attachment).await,
+        }
+    }
+
+    async fn update(
+        &self,
+        payload: &Payload,
+        details: Option<&Details>,
+        sequence_id: &str,
+    ) -> Result<(), SendError> {
+        match self {
+            Self::Ntfy(ntfy) => ntfy.update(payload, details, sequence_id).await,
+            _ => self.publish(payload, details, None).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chat_text, Backend, Details, Notifier};
+    use crate::helpers::test_server;
+    use crate::ntfy::{Credentials, Payload};
+    use crate::outcome::Outcome;
+
+    fn details() -> Details {
+        Details {
+            command: String::from("backup.sh"),
+            outcome: Outcome::Failure,
+            status: String::from("failed with exit code 3"),
+            exit_code: Some(3),
+            signal: None,
+            duration_ms: Some(3000),
+            host: String::from("robin@laptop"),
+            stdout: String::from("copying\n"),
+            stderr: String::from("disk full\n"),
+        }
+    }
+
+    #[test]
+    fn test_from_endpoint() {
+        let backend = |endpoint: &str| Backend::from_endpoint(endpoint, None, None);
+
+        assert!(matches!(backend("ntfy.sh"), Ok(Backend::Ntfy(_))));
+        assert!(matches!(
+            backend("https://ntfy.example.com"),
+            Ok(Backend::Ntfy(_))
+        ));
+        assert!(matches!(
+            backend("ntfy+http://localhost:8080"),
+            Ok(Backend::Ntfy(_))
+        ));
+
+        let Ok(Backend::Gotify(gotify)) = backend("gotify+http://localhost/gotify/AbC") else {
+            panic!("expected a Gotify backend");
+        };
+        assert_eq!(gotify.url.as_str(), "http://localhost/gotify/message");
+        assert_eq!(gotify.token, "AbC");
+
+        let token = Credentials::Token(String::from("tok"));
+        let Ok(Backend::Matrix(matrix)) =
+            Backend::from_endpoint("matrix://matrix.org/!room:matrix.org", Some(&token), None)
+        else {
+            panic!("expected a Matrix backend");
+        };
+        assert_eq!(
+            matrix.url.as_str(),
+            "https://matrix.org/_matrix/client/v3/rooms/!room:matrix.org/send/m.room.message/"
+        );
+        assert_eq!(matrix.token, "tok");
+
+        assert!(matches!(
+            backend("slack://hooks.slack.com/services/x"),
+            Ok(Backend::Slack(_))
+        ));
+        assert!(matches!(
+            backend("smtps://mail.example.com/me@example.com"),
+            Ok(Backend::Smtp(_))
+        ));
+
+        assert!(backend("matrix://matrix.org/!room:matrix.org").is_err());
+        assert!(backend("gotify://localhost").is_err());
+        assert!(backend("smtp://mail.example.com").is_err());
+        assert!(backend("irc://irc.example.com").is_err());
+    }
+
+    #[test]
+    fn test_chat_text() {
+        let payload = Payload::new("topic")
+            .title("backup.sh (3s)")
+            .message("0123456789");
+
+        assert_eq!(
+            chat_text(&payload, None, "*", 100),
+            "*backup.sh (3s)*\n```\n0123456789\n```"
+        );
+        assert_eq!(
+            chat_text(&payload.clone().markdown(true), None, "**", 4),
+            "**backup.sh (3s)**\n6789"
+        );
+
+        // the result rather than the (JSON) message, with only the end of the output
+        assert_eq!(
+            chat_text(&payload, Some(&details()), "*", 9),
+            "*backup.sh (3s)*\nfailed with exit code 3 after 3s on robin@laptop\n```\ndisk full\n```"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_webhook_details() {
+        let (url, requests) = test_server(200).await;
+        let endpoint = url.replacen("http://", "webhook+http://", 1);
+        let backend = Backend::from_endpoint(&endpoint, None, None).unwrap();
+        let payload = Payload::new("jobs").title("backup.sh").message("{}");
+
+        backend
+            .publish(&payload, Some(&details()), None)
+            .await
+            .unwrap();
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(request.contains(r#""title":"backup.sh""#));
+        assert!(request.contains(r#""result":{"command":"backup.sh","outcome":"failure""#));
+        assert!(request.contains(r#""exit_code":3"#));
+        assert!(request.contains(r#""duration_ms":3000"#));
+    }
+}
@@ -1,10 +1,475 @@
-pub use ntfy::{Dispatcher, Payload};
+use std::io::Write;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::Local;
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+pub use ntfy::payload::{Action, ActionType};
+pub use ntfy::{Auth, Payload, Priority};
+use reqwest::header::AUTHORIZATION;
+use reqwest::{Method, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
 
 use crate::constants::DEFAULT_NTFY_SERVER;
-use crate::helpers::normalize_url;
+use crate::helpers::{normalize_url, ResultToString};
+use crate::notifier::{http_client, Details, Notifier, SendError};
+
+/// Credentials for protected ntfy servers.
+#[derive(Clone)]
+pub enum Credentials {
+    /// Access token (`tk_...`), sent as a bearer token.
+    Token(String),
+    Basic {
+        user: String,
+        password: String,
+    },
+}
+
+impl Credentials {
+    /// `--token` takes precedence over `--user`/`--password`.
+    pub fn from_options(
+        token: Option<&str>,
+        user: Option<&str>,
+        password: Option<&str>,
+    ) -> Option<Self> {
+        match (token, user) {
+            (Some(token), _) if !token.is_empty() => Some(Self::Token(token.to_string())),
+            (_, Some(user)) if !user.is_empty() => Some(Self::Basic {
+                user: user.to_string(),
+                password: password.unwrap_or_default().to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_auth(&self) -> Auth {
+        match self {
+            Self::Token(token) => Auth::new("", token),
+            Self::Basic { user, password } => Auth::new(user, password),
+        }
+    }
+}
+
+/// A file that is uploaded with ntfy's `PUT` + `Filename` API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub filename: String,
+    #[serde(with = "base64_bytes")]
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    /// Attach `text` as `filename`, gzip-compressed (as `filename.gz`) if `gzip` is set.
+    pub fn from_text(
+        filename: &str,
+        text: &str,
+        gzip: bool,
+    ) -> Self {
+        if gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            // writing into a Vec can't fail
+            if encoder.write_all(text.as_bytes()).is_ok() {
+                if let Ok(data) = encoder.finish() {
+                    return Self {
+                        filename: format!("{filename}.gz"),
+                        data,
+                    };
+                }
+            }
+        }
+
+        Self {
+            filename: filename.to_string(),
+            data: text.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// Store binary data as base64 (e.g. in the spool).
+mod base64_bytes {
+    use base64::Engine;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::BASE64;
+
+    pub fn serialize<S: Serializer>(
+        data: &[u8],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        BASE64.decode(encoded).map_err(D::Error::custom)
+    }
+}
+
+/// Header values have to be ASCII, other text is sent RFC 2047 encoded (which ntfy understands).
+fn encode_header(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", BASE64.encode(value))
+    }
+}
+
+/// Parse an action button in ntfy's short format: `<view|http>, <label>, <url>[, clear=true][, body=<body>]`
+/// (`body` has to come last, it may contain commas).
+pub fn parse_action(definition: &str) -> Result<Action, String> {
+    let invalid = |reason: &str| format!("Invalid action '{definition}': {reason}");
+
+    let mut parts = definition.splitn(3, ',').map(str::trim);
+    let (Some(kind), Some(label), Some(rest)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid("expected `<view|http>, <label>, <url>`"));
+    };
+
+    let kind = match kind.to_lowercase().as_str() {
+        "view" => ActionType::View,
+        "http" => ActionType::Http,
+        _ => return Err(invalid("only `view` and `http` actions are supported")),
+    };
+
+    let (url, mut options) = rest.split_once(',').unwrap_or((rest, ""));
+    let url = Url::parse(url.trim()).map_err(|error| invalid(&error.to_string()))?;
+    let mut action = Action::new(kind, label, url);
+
+    while !options.trim().is_empty() {
+        let option = options.trim_start();
+
+        if let Some(body) = option.strip_prefix("body=") {
+            action = action.body(Value::String(body.to_string()));
+            break;
+        }
+
+        let (option, rest) = option.split_once(',').unwrap_or((option, ""));
+        action = match option.trim() {
+            "clear=true" => action.clear(true),
+            "clear=false" => action.clear(false),
+            other => return Err(invalid(&format!("unknown option `{other}`"))),
+        };
+        options = rest;
+    }
+
+    Ok(action)
+}
+
+/// ntfy client: messages are published as JSON, attachments are uploaded with a plain `PUT` request.
+/// Not the `ntfy` crate's `Dispatcher`: it can't send an icon or a sequence id, sends action types as `View`
+/// (ntfy expects `view`) and doesn't tell a rejected message from an unreachable server.
+pub struct Ntfy {
+    server: Url,
+    client: reqwest::Client,
+    credentials: Option<Credentials>,
+    /// Icon (url) of every message, which the `ntfy` crate's `Payload` has no field for.
+    icon: Option<String>,
+}
+
+/// A delay like `30m` as the time it ends, counted from now (when the message is sent).
+/// Anything else (`10am`, a unix timestamp, ...) is left to the server.
+fn resolve_delay(delay: &str) -> String {
+    humantime::parse_duration(delay).map_or_else(
+        |_| delay.to_string(),
+        |duration| (Local::now() + duration).timestamp().to_string(),
+    )
+}
+
+/// The JSON that is published for a payload.
+fn payload_json(
+    payload: &Payload,
+    icon: Option<&str>,
+) -> Result<Value, String> {
+    let mut json = serde_json::to_value(payload).map_err_to_string()?;
+
+    // the `ntfy` crate serializes action types as `View`, while ntfy expects `view`
+    if let Some(actions) = json["actions"].as_array_mut() {
+        for action in actions {
+            if let Some(kind) = action["action"].as_str() {
+                action["action"] = kind.to_lowercase().into();
+            }
+        }
+    }
+
+    if let Some(icon) = icon {
+        json["icon"] = icon.into();
+    }
+
+    if let Some(delay) = &payload.delay {
+        json["delay"] = resolve_delay(delay).into();
+    }
+
+    Ok(json)
+}
+
+impl Ntfy {
+    #[must_use]
+    pub fn with_icon(
+        mut self,
+        icon: Option<String>,
+    ) -> Self {
+        self.icon = icon;
+        self
+    }
+
+    fn authorize(
+        &self,
+        request: RequestBuilder,
+    ) -> RequestBuilder {
+        match &self.credentials {
+            Some(Credentials::Token(token)) => {
+                request.header(AUTHORIZATION, format!("Bearer {token}"))
+            },
+            Some(credentials) => {
+                let auth = credentials.to_auth().as_base64();
+                request.header(AUTHORIZATION, format!("Basic {auth}"))
+            },
+            None => request,
+        }
+    }
+
+    /// Publish the payload as JSON, replacing earlier messages with the same `sequence_id` if there is one.
+    pub async fn send(
+        &self,
+        payload: &Payload,
+        sequence_id: Option<&str>,
+    ) -> Result<(), SendError> {
+        let json = payload_json(payload, self.icon.as_deref())?;
+
+        let mut request = self.client.post(self.server.clone()).json(&json);
+        if payload.markdown {
+            request = request.header("Markdown", "yes");
+        }
+        if let Some(sequence_id) = sequence_id {
+            request = request.header("X-Sequence-ID", sequence_id);
+        }
 
-pub fn setup_ntfy(server: &str) -> Dispatcher {
+        let response = self.authorize(request).send().await.map_err_to_string()?;
+
+        if !response.status().is_success() {
+            return Err(SendError::status("Sending failed", response.status()));
+        }
+
+        Ok(())
+    }
+
+    /// Request to the payload's topic, with its title, message, priority and tags as headers.
+    fn request(
+        &self,
+        method: Method,
+        payload: &Payload,
+    ) -> Result<RequestBuilder, String> {
+        let url = self.server.join(&payload.topic).map_err_to_string()?;
+
+        let mut request = self
+            .client
+            .request(method, url)
+            .header("Priority", (payload.priority.clone() as u8).to_string());
+
+        if let Some(title) = &payload.title {
+            request = request.header("Title", encode_header(title));
+        }
+
+        if !payload.message.is_empty() {
+            request = request.header("Message", encode_header(&payload.message));
+        }
+
+        if let Some(tags) = &payload.tags {
+            request = request.header("Tags", encode_header(&tags.join(",")));
+        }
+
+        if payload.markdown {
+            request = request.header("Markdown", "yes");
+        }
+
+        if let Some(click) = &payload.click {
+            request = request.header("Click", click.as_str());
+        }
+
+        if let Some(icon) = &self.icon {
+            request = request.header("Icon", icon);
+        }
+
+        if let Some(delay) = &payload.delay {
+            request = request.header("Delay", encode_header(&resolve_delay(delay)));
+        }
+
+        if let Some(email) = &payload.email {
+            request = request.header("Email", encode_header(email));
+        }
+
+        Ok(self.authorize(request))
+    }
+
+    /// Upload a file to the payload's topic, using the payload's title, message, priority and tags.
+    pub async fn upload(
+        &self,
+        payload: &Payload,
+        attachment: &Attachment,
+    ) -> Result<(), SendError> {
+        let response = self
+            .request(Method::PUT, payload)?
+            .header("Filename", encode_header(&attachment.filename))
+            .body(attachment.data.clone())
+            .send()
+            .await
+            .map_err_to_string()?;
+
+        if !response.status().is_success() {
+            return Err(SendError::status(
+                &format!("Uploading {} failed", attachment.filename),
+                response.status(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Notifier for Ntfy {
+    /// Send a message that replaces earlier messages with the same sequence id
+    /// (on servers that support updating notifications, others show them as new messages).
+    async fn update(
+        &self,
+        payload: &Payload,
+        _details: Option<&Details>,
+        sequence_id: &str,
+    ) -> Result<(), SendError> {
+        self.send(payload, Some(sequence_id)).await
+    }
+
+    /// Send the payload, as an upload if it comes with an attachment. The message already has the details.
+    async fn publish(
+        &self,
+        payload: &Payload,
+        _details: Option<&Details>,
+        attachment: Option<&Attachment>,
+    ) -> Result<(), SendError> {
+        match attachment {
+            Some(attachment) => self.upload(payload, attachment).await,
+            None => self.send(payload, None).await,
+        }
+    }
+}
+
+/// The server url, ending with a slash so topics can be joined to it.
+fn server_url(server: &str) -> Url {
     let server_uri = normalize_url(server, DEFAULT_NTFY_SERVER);
 
-    Dispatcher::builder(server_uri).build().unwrap()
+    let mut server = Url::parse(&server_uri).unwrap();
+    if !server.path().ends_with('/') {
+        server.set_path(&format!("{}/", server.path()));
+    }
+
+    server
+}
+
+/// `{server}/{topic}`, where messages to the topic can be published.
+pub fn topic_url(
+    server: &str,
+    topic: &str,
+) -> Result<Url, String> {
+    server_url(server).join(topic).map_err_to_string()
+}
+
+pub fn setup_ntfy(
+    server: &str,
+    credentials: Option<&Credentials>,
+) -> Ntfy {
+    Ntfy {
+        server: server_url(server),
+        client: http_client(),
+        credentials: credentials.cloned(),
+        icon: None,
+    }
+}
+
+/// Priority names as used in the config file (and on the command line).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PriorityLevel {
+    Min,
+    Low,
+    Default,
+    High,
+    #[serde(alias = "urgent")]
+    #[value(alias = "urgent")]
+    Max,
+}
+
+impl From<PriorityLevel> for Priority {
+    fn from(level: PriorityLevel) -> Self {
+        match level {
+            PriorityLevel::Min => Self::Min,
+            PriorityLevel::Low => Self::Low,
+            PriorityLevel::Default => Self::Default,
+            PriorityLevel::High => Self::High,
+            PriorityLevel::Max => Self::Max,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Local;
+
+    use super::{parse_action, payload_json, resolve_delay, setup_ntfy, Payload};
+    use crate::helpers::test_server;
+    use crate::notifier::Notifier;
+
+    #[test]
+    fn test_parse_action() {
+        let action = parse_action(
+            "http, Re-run, https://ci.example.com/run, clear=true, body={\"a\": 1, \"b\": 2}",
+        )
+        .unwrap();
+        assert_eq!(action.label, "Re-run");
+        assert_eq!(action.url.as_str(), "https://ci.example.com/run");
+        assert_eq!(action.clear, Some(true));
+        assert_eq!(action.body.unwrap(), "{\"a\": 1, \"b\": 2}");
+
+        let json = payload_json(
+            &Payload::new("topic")
+                .actions([parse_action("view, Open, https://example.com").unwrap()]),
+            Some("https://example.com/icon.png"),
+        )
+        .unwrap();
+        assert_eq!(json["actions"][0]["action"], "view");
+        assert_eq!(json["icon"], "https://example.com/icon.png");
+
+        assert!(parse_action("view, Open").is_err());
+        assert!(parse_action("broadcast, Open, https://example.com").is_err());
+        assert!(parse_action("view, Open, not a url").is_err());
+        assert!(parse_action("view, Open, https://example.com, clear=maybe").is_err());
+    }
+
+    #[test]
+    fn test_resolve_delay() {
+        let end = Local::now().timestamp() + 90 * 60;
+        let resolved: i64 = resolve_delay("1h 30m").parse().unwrap();
+        assert!((end..end + 5).contains(&resolved));
+
+        assert_eq!(resolve_delay("tomorrow, 8am"), "tomorrow, 8am");
+        assert_eq!(resolve_delay("1700000000"), "1700000000");
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let (server, requests) = test_server(200).await;
+        let ntfy = setup_ntfy(&server, None);
+
+        let payload = Payload::new("jobs").title("backup.sh (3s)").message("done");
+        ntfy.update(&payload, None, "ntfy-log-1-2").await.unwrap();
+        ntfy.publish(&payload, None, None).await.unwrap();
+
+        let requests = requests.lock().unwrap().clone();
+        assert!(requests[0]
+            .to_lowercase()
+            .contains("x-sequence-id: ntfy-log-1-2\r\n"));
+        assert!(requests[0].contains("\"title\":\"backup.sh (3s)\""));
+        assert!(!requests[1].to_lowercase().contains("x-sequence-id"));
+    }
 }
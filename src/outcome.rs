@@ -0,0 +1,221 @@
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::command::CommandResult;
+use crate::config::OutcomeSettings;
+
+/// How a command ended, which decides the priority and secondary topic of its notification.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    #[default]
+    Success,
+    /// Succeeded, but one of the `warn_if_*` rules matched.
+    Warning,
+    Failure,
+    Timeout,
+}
+
+impl Outcome {
+    /// The outcome based on the exit code alone (before any rules are applied).
+    pub const fn from_exit_code(
+        exit_code: i32,
+        timed_out: bool,
+    ) -> Self {
+        if timed_out {
+            Self::Timeout
+        } else if exit_code == 0 {
+            Self::Success
+        } else {
+            Self::Failure
+        }
+    }
+}
+
+/// `[outcome]` settings with their patterns compiled (`^` and `$` match at every line).
+pub struct OutcomeRules {
+    ok_exit_codes: Vec<i32>,
+    fail_if_stdout_matches: Option<Regex>,
+    fail_if_stderr_matches: Option<Regex>,
+    warn_if_stdout_matches: Option<Regex>,
+    warn_if_stderr_matches: Option<Regex>,
+    warn_if_stderr_nonempty: bool,
+}
+
+fn compile(
+    name: &str,
+    pattern: Option<&String>,
+) -> Result<Option<Regex>, String> {
+    pattern
+        .map(|pattern| {
+            RegexBuilder::new(pattern)
+                .multi_line(true)
+                .build()
+                .map_err(|error| format!("Invalid `{name}` pattern: {error}"))
+        })
+        .transpose()
+}
+
+/// The first pattern that matches `output`, as a reason like "stdout matched `ERROR`".
+fn matched(
+    name: &str,
+    pattern: Option<&Regex>,
+    output: &str,
+) -> Option<String> {
+    let pattern = pattern?;
+    pattern
+        .is_match(output)
+        .then(|| format!("{name} matched `{pattern}`"))
+}
+
+impl OutcomeRules {
+    pub fn new(settings: &OutcomeSettings) -> Result<Self, String> {
+        Ok(Self {
+            ok_exit_codes: settings.ok_exit_codes.clone(),
+            fail_if_stdout_matches: compile(
+                "fail_if_stdout_matches",
+                settings.fail_if_stdout_matches.as_ref(),
+            )?,
+            fail_if_stderr_matches: compile(
+                "fail_if_stderr_matches",
+                settings.fail_if_stderr_matches.as_ref(),
+            )?,
+            warn_if_stdout_matches: compile(
+                "warn_if_stdout_matches",
+                settings.warn_if_stdout_matches.as_ref(),
+            )?,
+            warn_if_stderr_matches: compile(
+                "warn_if_stderr_matches",
+                settings.warn_if_stderr_matches.as_ref(),
+            )?,
+            warn_if_stderr_nonempty: settings.warn_if_stderr_nonempty,
+        })
+    }
+
+    /// The outcome of a result, and why it's not what the exit code says (if that's the case).
+    /// Timeouts always count as failures, signals unless their exit code (`128 + signal`) is one of `ok_exit_codes`
+    /// (e.g. 141 for SIGPIPE, when `| head` stopped reading).
+    pub fn classify(
+        &self,
+        result: &CommandResult,
+    ) -> (Outcome, Option<String>) {
+        if result.timed_out {
+            return (Outcome::Timeout, None);
+        }
+
+        if !self.ok_exit_codes.contains(&result.exit_code) {
+            return (Outcome::Failure, None);
+        }
+
+        let failure = matched(
+            "stdout",
+            self.fail_if_stdout_matches.as_ref(),
+            &result.stdout,
+        )
+        .or_else(|| {
+            matched(
+                "stderr",
+                self.fail_if_stderr_matches.as_ref(),
+                &result.stderr,
+            )
+        });
+
+        if let Some(reason) = failure {
+            return (Outcome::Failure, Some(reason));
+        }
+
+        let warning = matched(
+            "stdout",
+            self.warn_if_stdout_matches.as_ref(),
+            &result.stdout,
+        )
+        .or_else(|| {
+            matched(
+                "stderr",
+                self.warn_if_stderr_matches.as_ref(),
+                &result.stderr,
+            )
+        })
+        .or_else(|| {
+            (self.warn_if_stderr_nonempty && !result.stderr.trim().is_empty())
+                .then(|| String::from("stderr is not empty"))
+        });
+
+        warning.map_or((Outcome::Success, None), |reason| {
+            (Outcome::Warning, Some(reason))
+        })
+    }
+
+    pub fn apply(
+        &self,
+        result: &mut CommandResult,
+    ) {
+        (result.outcome, result.outcome_reason) = self.classify(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Outcome, OutcomeRules};
+    use crate::command::CommandResult;
+    use crate::config::OutcomeSettings;
+
+    fn result(
+        exit_code: i32,
+        stdout: &str,
+        stderr: &str,
+    ) -> CommandResult {
+        let mut result = CommandResult::from_stdin(stdout.to_string());
+        result.exit_code = exit_code;
+        result.stderr = stderr.to_string();
+        result
+    }
+
+    #[test]
+    fn test_classify() {
+        let rules = OutcomeRules::new(&OutcomeSettings {
+            ok_exit_codes: vec![0, 1],
+            fail_if_stdout_matches: Some(String::from("^0 files transferred$")),
+            warn_if_stderr_nonempty: true,
+            ..OutcomeSettings::default()
+        })
+        .unwrap();
+
+        assert_eq!(rules.classify(&result(0, "ok", "")).0, Outcome::Success);
+        assert_eq!(rules.classify(&result(1, "ok", "")).0, Outcome::Success);
+        assert_eq!(rules.classify(&result(2, "ok", "")).0, Outcome::Failure);
+        assert_eq!(
+            rules.classify(&result(0, "done\n0 files transferred\n", "")),
+            (
+                Outcome::Failure,
+                Some(String::from("stdout matched `^0 files transferred$`"))
+            )
+        );
+        assert_eq!(
+            rules.classify(&result(0, "ok", "deprecated")).0,
+            Outcome::Warning
+        );
+
+        // a signal is reported with its exit code as well
+        let mut pipe_closed = result(141, "", "");
+        pipe_closed.signal = Some(libc::SIGPIPE);
+        assert_eq!(rules.classify(&pipe_closed).0, Outcome::Failure);
+
+        let sigpipe_ok = OutcomeRules::new(&OutcomeSettings {
+            ok_exit_codes: vec![0, 141],
+            ..OutcomeSettings::default()
+        })
+        .unwrap();
+        assert_eq!(sigpipe_ok.classify(&pipe_closed).0, Outcome::Success);
+
+        let mut timed_out = result(124, "", "");
+        timed_out.timed_out = true;
+        assert_eq!(rules.classify(&timed_out).0, Outcome::Timeout);
+
+        assert!(OutcomeRules::new(&OutcomeSettings {
+            warn_if_stdout_matches: Some(String::from("(")),
+            ..OutcomeSettings::default()
+        })
+        .is_err());
+    }
+}
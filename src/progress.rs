@@ -0,0 +1,168 @@
+use std::fmt::Write;
+
+use chrono::Utc;
+use futures_util::future::join_all;
+
+use crate::command::{CommandResult, Progress};
+use crate::constants::{PROGRESS_LINES, PROGRESS_MAX_BYTES};
+use crate::format::HostInfo;
+use crate::helpers::{format_duration, tail};
+use crate::log::GlobalLogger;
+use crate::ntfy::{Payload, Priority};
+use crate::redact::Redactor;
+use crate::target::Destination;
+
+/// `--notify-start` and `--progress-every` messages for a running command.
+/// They share a sequence id, so every message replaces the previous one (where the server supports it).
+pub struct LiveUpdates<'a> {
+    destinations: &'a [Destination<'a>],
+    redactor: &'a Redactor,
+    command: String,
+    host: HostInfo,
+    sequence_id: String,
+}
+
+impl<'a> LiveUpdates<'a> {
+    pub fn new(
+        destinations: &'a [Destination<'a>],
+        redactor: &'a Redactor,
+        command: &str,
+    ) -> Self {
+        let sequence_id = format!(
+            "ntfy-log-{}-{}",
+            std::process::id(),
+            Utc::now().timestamp_millis()
+        );
+
+        Self {
+            destinations,
+            redactor,
+            command: redactor.redact(command).into_owned(),
+            host: HostInfo::current(),
+            sequence_id,
+        }
+    }
+
+    /// Send the update to every destination at once.
+    async fn send(
+        &self,
+        title: String,
+        message: String,
+    ) {
+        let updates = self.destinations.iter().map(|destination| async {
+            let payload = Payload::new(&destination.target.topic)
+                .title(title.clone())
+                .message(message.clone())
+                .priority(Priority::Low);
+
+            if let Err(error) = destination
+                .delivery
+                .send_update(&payload, &self.sequence_id)
+                .await
+            {
+                GlobalLogger::warn(format!(
+                    "Could not send update to {}: {error}",
+                    destination.target
+                ));
+            }
+        });
+
+        join_all(updates).await;
+    }
+
+    /// The final notification replaces the last update by sending it with this sequence id.
+    pub fn sequence_id(&self) -> &str {
+        &self.sequence_id
+    }
+
+    /// Sent right before the command is started.
+    pub async fn started(&self) {
+        let message = format!("Started on {}@{}", self.host.user, self.host.hostname);
+        self.send(format!("Started: {}", self.command), message)
+            .await;
+    }
+
+    /// Replaces the last update when there's no notification for the result (see `--notify`), at low priority
+    /// like the updates themselves.
+    pub async fn finished(
+        &self,
+        result: &CommandResult,
+    ) {
+        let message = format!(
+            "{} on {}@{}",
+            result.status_text(),
+            self.host.user,
+            self.host.hostname
+        );
+        let title = format!(
+            "Finished: {} ({})",
+            self.command,
+            format_duration(result.duration())
+        );
+
+        self.send(title, message).await;
+    }
+
+    /// Elapsed time and the last lines of output.
+    pub async fn progress(
+        &self,
+        progress: &Progress,
+    ) {
+        let elapsed = format_duration(progress.elapsed);
+
+        let mut message = format!(
+            "Running for {elapsed} on {}@{}",
+            self.host.user, self.host.hostname
+        );
+
+        for (name, output) in [("stdout", &progress.stdout), ("stderr", &progress.stderr)] {
+            // redacted first, so a secret that starts before the last lines is still recognized
+            let output = self.redactor.redact(output);
+            let recent = tail(&output, PROGRESS_LINES, PROGRESS_MAX_BYTES);
+
+            if !recent.trim().is_empty() {
+                let _ = write!(message, "\n\n{name}:\n{recent}");
+            }
+        }
+
+        self.send(format!("Running: {} ({elapsed})", self.command), message)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LiveUpdates;
+    use crate::command::CommandResult;
+    use crate::config::{RedactSettings, Settings};
+    use crate::helpers::test_server;
+    use crate::redact::Redactor;
+    use crate::target::{Destination, Target};
+
+    #[tokio::test]
+    async fn test_finished() {
+        let (server, requests) = test_server(200).await;
+        let settings = Settings::default();
+        let targets = [Target {
+            endpoint: server,
+            topic: String::from("backups"),
+        }];
+        let destinations = Destination::all(&targets, &settings).unwrap();
+        let redactor = Redactor::new(&RedactSettings::default()).unwrap();
+
+        let live = LiveUpdates::new(&destinations, &redactor, "./backup.sh");
+        live.started().await;
+        live.finished(&CommandResult::from_stdin(String::new()))
+            .await;
+
+        // both with the same sequence id, so the start message doesn't stay on the topic
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 2);
+        let header = format!("x-sequence-id: {}", live.sequence_id());
+        assert!(requests
+            .iter()
+            .all(|request| request.to_lowercase().contains(&header)));
+        assert!(requests[1].contains(r#""title":"Finished: ./backup.sh ("#));
+        assert!(requests[1].contains(r#""message":"succeeded on "#));
+    }
+}
@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+
+/// Size of the terminal when ntfy-log's own stdout isn't one.
+const DEFAULT_SIZE: (u16, u16) = (24, 80);
+
+/// The size of the terminal on stdout, so the command's output fits the window.
+fn window_size() -> libc::winsize {
+    let mut size = libc::winsize {
+        ws_row: DEFAULT_SIZE.0,
+        ws_col: DEFAULT_SIZE.1,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    // SAFETY: `size` is a valid winsize for TIOCGWINSZ to write to, it's left alone when stdout isn't a terminal.
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if result != 0 || size.ws_row == 0 || size.ws_col == 0 {
+        size.ws_row = DEFAULT_SIZE.0;
+        size.ws_col = DEFAULT_SIZE.1;
+    }
+
+    size
+}
+
+/// Open a pseudo-terminal: the master (read by ntfy-log) and the slave (stdout and stderr of the command).
+pub fn open() -> io::Result<(Master, OwnedFd)> {
+    let mut master = -1;
+    let mut slave = -1;
+    let size = window_size();
+
+    // SAFETY: both fds are written by openpty, the name and termios may be null.
+    let result = unsafe {
+        libc::openpty(
+            &raw mut master,
+            &raw mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            &raw const size,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: openpty succeeded, so both are open fds that nothing else owns.
+    let (master, slave) = unsafe { (File::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+
+    // SAFETY: fcntl on an fd we own.
+    let flags = unsafe { libc::fcntl(master.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0
+        || unsafe { libc::fcntl(master.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((Master(AsyncFd::new(master)?), slave))
+}
+
+/// The master side of the pseudo-terminal, where the output of the command arrives.
+/// Reading fails (with EIO) once the command and everything it started closed the terminal.
+pub struct Master(AsyncFd<File>);
+
+impl Master {
+    /// Keep the size of the pseudo-terminal in sync with the terminal on stdout when that's resized (`SIGWINCH`),
+    /// the kernel then signals the command. Runs until the returned task is aborted.
+    pub fn follow_window_size(&self) -> io::Result<JoinHandle<()>> {
+        let master = self.0.get_ref().try_clone()?;
+        let mut window_change = signal(SignalKind::window_change())?;
+
+        Ok(tokio::spawn(async move {
+            while window_change.recv().await.is_some() {
+                let size = window_size();
+                // SAFETY: TIOCSWINSZ only reads `size`, and `master` is an open fd.
+                unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &raw const size) };
+            }
+        }))
+    }
+}
+
+impl AsyncRead for Master {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+
+            match guard.try_io(|file| file.get_ref().read(buf.initialize_unfilled())) {
+                Ok(Ok(size)) => {
+                    buf.advance(size);
+                    return Poll::Ready(Ok(()));
+                },
+                Ok(Err(error)) => return Poll::Ready(Err(error)),
+                Err(_would_block) => {},
+            }
+        }
+    }
+}
+
+/// Skip an escape sequence (the `ESC` is already consumed): CSI (`ESC [ ... m`), OSC (`ESC ] ... BEL`)
+/// or a short one like `ESC ( B`.
+fn skip_escape(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) {
+    match chars.next() {
+        Some('[') => {
+            // parameters and intermediates, up to the final byte (`@` to `~`)
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        },
+        Some(']' | 'P' | '_' | '^') => {
+            // strings end with BEL or `ESC \`
+            while let Some(c) = chars.next() {
+                if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                    break;
+                }
+            }
+        },
+        Some('(' | ')' | '*' | '+' | '#' | '%') => {
+            chars.next();
+        },
+        _ => {},
+    }
+}
+
+/// What the output of a terminal program would look like as plain text: escape sequences (colors, cursor movement)
+/// are removed and a line redrawn with `\r` (e.g. a progress bar) only keeps what was drawn last.
+pub fn clean_output(output: &str) -> String {
+    let mut cleaned = String::with_capacity(output.len());
+    let mut line = String::new();
+    let mut chars = output.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => skip_escape(&mut chars),
+            '\n' => {
+                cleaned.push_str(&line);
+                cleaned.push('\n');
+                line.clear();
+            },
+            // the terminal turns `\n` into `\r\n`, only a `\r` with more text after it redraws the line
+            '\r' => {
+                if !matches!(chars.peek(), Some('\r' | '\n') | None) {
+                    line.clear();
+                }
+            },
+            '\x08' => {
+                line.pop();
+            },
+            '\t' => line.push(c),
+            c if c.is_control() => {},
+            c => line.push(c),
+        }
+    }
+
+    cleaned.push_str(&line);
+    cleaned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::clean_output;
+
+    #[test]
+    fn test_clean_output() {
+        assert_eq!(
+            clean_output("\x1b[1m\x1b[32m   Compiling\x1b[0m ntfy-log\r\n"),
+            "   Compiling ntfy-log\n"
+        );
+        assert_eq!(
+            clean_output("downloading  10%\rdownloading  50%\r\x1b[2Kdownloading 100%\r\ndone\r\n"),
+            "downloading 100%\ndone\n"
+        );
+        assert_eq!(
+            clean_output("\x1b]0;window title\x07\x1b(Bprompt\tok\x07\r\r\n"),
+            "prompt\tok\n"
+        );
+        assert_eq!(clean_output("ab\x08c\rno newline"), "no newline");
+    }
+}
@@ -0,0 +1,233 @@
+use lettre::message::header::{ContentType, HeaderName, HeaderValue};
+use lettre::message::{Attachment as MailAttachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials as Login;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use url::Url;
+
+use crate::constants::SMTP_TIMEOUT;
+use crate::format::HostInfo;
+use crate::helpers::ResultToString;
+use crate::notifier::{Details, Notifier, SendError};
+use crate::ntfy::{Attachment, Credentials, Payload};
+
+/// `smtp://[user:password@]host[:port]/<to>[?from=<from>]` (STARTTLS when offered, required with credentials)
+/// or `smtps://...` (TLS from the start).
+pub struct Smtp {
+    host: String,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Mailbox,
+}
+
+/// An address as it's used in the headers and the SMTP commands.
+fn parse_address(
+    address: &str,
+    what: &str,
+) -> Result<Mailbox, String> {
+    address
+        .parse()
+        .map_err(|error| format!("Invalid {what} address for smtp: {address:?} ({error})"))
+}
+
+impl Smtp {
+    pub fn from_endpoint(
+        endpoint: &str,
+        credentials: Option<&Credentials>,
+    ) -> Result<Self, String> {
+        let url =
+            Url::parse(endpoint).map_err(|error| format!("Invalid smtp endpoint: {error}"))?;
+        let implicit_tls = url.scheme() == "smtps";
+
+        let host = url
+            .host_str()
+            .ok_or("The smtp endpoint needs a host")?
+            .to_string();
+
+        let to = url.path().trim_matches('/').to_string();
+        if !to.contains('@') {
+            return Err(String::from(
+                "The smtp endpoint needs a recipient, e.g. smtps://mail.example.com/me@example.com",
+            ));
+        }
+
+        let from = url
+            .query_pairs()
+            .find(|(key, _)| key == "from")
+            .map_or_else(
+                || format!("ntfy-log@{}", HostInfo::current().hostname),
+                |(_, from)| from.into_owned(),
+            );
+
+        let login = match (url.username(), credentials) {
+            ("", Some(Credentials::Basic { user, password })) => {
+                Some(Login::new(user.clone(), password.clone()))
+            },
+            ("", _) => None,
+            (user, _) => Some(Login::new(
+                user.to_string(),
+                url.password().unwrap_or_default().to_string(),
+            )),
+        };
+
+        let invalid = |error| format!("Invalid smtp endpoint: {error}");
+        let builder = match (implicit_tls, &login) {
+            (true, _) => AsyncSmtpTransport::<Tokio1Executor>::relay(&host).map_err(invalid)?,
+            // never send the credentials unencrypted
+            (false, Some(_)) => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host).map_err(invalid)?
+            },
+            (false, None) => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host).tls(
+                Tls::Opportunistic(TlsParameters::new(host.clone()).map_err(invalid)?),
+            ),
+        };
+
+        let mut builder = builder
+            .port(url.port().unwrap_or(if implicit_tls { 465 } else { 25 }))
+            .timeout(Some(SMTP_TIMEOUT));
+        if let Some(login) = login {
+            builder = builder.credentials(login);
+        }
+
+        Ok(Self {
+            from: parse_address(&from, "sender")?,
+            to: parse_address(&to, "recipient")?,
+            transport: builder.build(),
+            host,
+        })
+    }
+
+    /// The email, with the result (or the message) as text and the attachment (if any) as a file.
+    fn email(
+        &self,
+        payload: &Payload,
+        details: Option<&Details>,
+        attachment: Option<&Attachment>,
+    ) -> Result<Message, String> {
+        let builder = Message::builder()
+            .from(self.from.clone())
+            .to(self.to.clone())
+            .subject(payload.title.as_deref().unwrap_or("ntfy-log"))
+            .date_now();
+
+        let text =
+            SinglePart::plain(details.map_or_else(|| payload.message.clone(), Details::text));
+
+        let mut email = match attachment {
+            None => builder.singlepart(text),
+            Some(attachment) => builder.multipart(MultiPart::mixed().singlepart(text).singlepart(
+                MailAttachment::new(attachment.filename.clone()).body(
+                    attachment.data.clone(),
+                    ContentType::parse("application/octet-stream").map_err_to_string()?,
+                ),
+            )),
+        }
+        .map_err_to_string()?;
+
+        // ntfy's 1-5 to X-Priority's 5-1
+        let priority = 6 - payload.priority.clone() as u8;
+        email.headers_mut().insert_raw(HeaderValue::new(
+            HeaderName::new_from_ascii_str("X-Priority"),
+            priority.to_string(),
+        ));
+
+        Ok(email)
+    }
+}
+
+impl Notifier for Smtp {
+    /// Permanent failures (5xx, e.g. an unknown recipient) are rejections, the rest is worth another try.
+    async fn publish(
+        &self,
+        payload: &Payload,
+        details: Option<&Details>,
+        attachment: Option<&Attachment>,
+    ) -> Result<(), SendError> {
+        let email = self
+            .email(payload, details, attachment)
+            .map_err(SendError::Rejected)?;
+
+        match tokio::time::timeout(SMTP_TIMEOUT, self.transport.send(email)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(error)) if error.is_permanent() => Err(SendError::Rejected(format!(
+                "Sending email via {} failed: {error}",
+                self.host
+            ))),
+            Ok(Err(error)) => Err(SendError::Temporary(format!(
+                "Sending email via {} failed: {error}",
+                self.host
+            ))),
+            Err(_) => Err(SendError::Temporary(format!(
+                "Sending email via {} timed out",
+                self.host
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Smtp;
+    use crate::notifier::Details;
+    use crate::ntfy::{Attachment, Payload};
+    use crate::outcome::Outcome;
+
+    #[test]
+    fn test_email() {
+        let smtp = Smtp::from_endpoint(
+            "smtp://mail.example.com/me@example.com?from=ci@example.com",
+            None,
+        )
+        .unwrap();
+
+        let payload = Payload::new("jobs")
+            .title("hello\r\nBcc: victim@evil.com")
+            .message("line 1\n.hidden");
+        let email = smtp.email(&payload, None, None).unwrap().formatted();
+        let email = String::from_utf8_lossy(&email);
+
+        assert!(email.contains("From: ci@example.com\r\n"));
+        assert!(email.contains("To: me@example.com\r\n"));
+        assert!(email.contains("X-Priority: 3\r\n"));
+        assert!(!email.contains("\r\nBcc:"));
+        assert!(email.contains("line 1\r\n.hidden"));
+
+        let details = Details {
+            command: String::from("backup.sh"),
+            outcome: Outcome::Failure,
+            status: String::from("failed with exit code 3"),
+            exit_code: Some(3),
+            signal: None,
+            duration_ms: Some(3000),
+            host: String::from("robin@laptop"),
+            stdout: String::from("copying\n"),
+            stderr: String::from("disk full\n"),
+        };
+        let payload = Payload::new("jobs").title("Geslaagd ✅").message("{}");
+        let attachment = Attachment {
+            filename: String::from("stdout.log"),
+            data: b"output".to_vec(),
+        };
+        let email = smtp
+            .email(&payload, Some(&details), Some(&attachment))
+            .unwrap()
+            .formatted();
+        let email = String::from_utf8_lossy(&email);
+
+        assert!(email.contains("Subject: Geslaagd =?utf-8?b?4pyF?=\r\n"));
+        assert!(email.contains("Content-Type: multipart/mixed;"));
+        assert!(email.contains(
+            "failed with exit code 3 after 3s on robin@laptop\r\n\r\ncopying\r\ndisk full"
+        ));
+        assert!(email.contains("filename=\"stdout.log\""));
+        assert!(email.contains("\r\n\r\noutput\r\n"));
+
+        for endpoint in [
+            "smtp://mail.example.com/me@example.com?from=a@b.c%0D%0ARCPT%20TO:<x@y.z>",
+            "smtp://mail.example.com/me@example.com?from=a@b.c>",
+            "smtp://mail.example.com/nobody",
+        ] {
+            assert!(Smtp::from_endpoint(endpoint, None).is_err(), "{endpoint}");
+        }
+    }
+}
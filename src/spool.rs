@@ -0,0 +1,378 @@
+use std::collections::HashSet;
+use std::fs::{self, DirBuilder, File};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::constants::{SPOOL_BACKOFF_BASE, SPOOL_BACKOFF_MAX, SPOOL_MAX_AGE};
+use crate::delivery::publish_to;
+use crate::helpers::{redact_url, state_home, ResultToString};
+use crate::log::GlobalLogger;
+use crate::notifier::{Backend, Details, SendError};
+use crate::ntfy::{Attachment, Credentials, Payload};
+
+/// A notification that could not be delivered yet.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpoolEntry {
+    pub endpoint: String,
+    pub payload: Payload,
+    /// `Payload.markdown` is not serialized by the ntfy crate, so it's stored separately.
+    #[serde(default)]
+    pub markdown: bool,
+    /// The result for the services that format it themselves, see `Details`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Details>,
+    /// The icon is not part of `Payload` either.
+    #[serde(default)]
+    pub icon: Option<String>,
+    #[serde(default)]
+    pub attachment: Option<Attachment>,
+    /// Replaces the earlier messages with this sequence id when it's delivered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence_id: Option<String>,
+    pub queued_at: String,
+    pub attempts: u32,
+    /// Unix timestamp after which the next attempt may be made.
+    pub next_attempt: i64,
+}
+
+impl SpoolEntry {
+    /// Whether it has been queued for longer than `SPOOL_MAX_AGE`, and is no longer worth retrying.
+    fn expired(&self) -> bool {
+        DateTime::parse_from_rfc3339(&self.queued_at).is_ok_and(|queued_at| {
+            Utc::now()
+                .signed_duration_since(queued_at)
+                .to_std()
+                .is_ok_and(|age| age > SPOOL_MAX_AGE)
+        })
+    }
+}
+
+/// Outcome of flushing the spool.
+#[derive(Debug, Default)]
+pub struct FlushResult {
+    pub delivered: usize,
+    pub remaining: usize,
+    /// Rejected by the service, or given up on after `SPOOL_MAX_AGE`, and moved to `rejected/`.
+    pub rejected: usize,
+}
+
+/// On-disk queue of undelivered notifications, delivered oldest first.
+pub struct Spool {
+    dir: PathBuf,
+}
+
+/// Exclusive lock on the spool directory, released when dropped (or when the process exits).
+struct SpoolLock {
+    _file: File,
+}
+
+impl SpoolLock {
+    /// `None` if another `ntfy-log` process is currently flushing.
+    fn try_acquire(dir: &Path) -> Option<Self> {
+        let file = File::create(dir.join(".lock")).ok()?;
+
+        // SAFETY: the file descriptor is valid for as long as `file` lives.
+        let locked = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0;
+
+        locked.then_some(Self { _file: file })
+    }
+}
+
+/// Delay before the next attempt: `SPOOL_BACKOFF_BASE * 2^attempts`, capped at `SPOOL_BACKOFF_MAX`.
+pub fn backoff(attempts: u32) -> Duration {
+    let factor = 2_u32.saturating_pow(attempts);
+    SPOOL_BACKOFF_BASE
+        .saturating_mul(factor)
+        .min(SPOOL_BACKOFF_MAX)
+}
+
+/// `$XDG_STATE_HOME/ntfy-log/spool`, falling back to `~/.local/state/ntfy-log/spool`.
+pub fn default_spool_dir() -> Option<PathBuf> {
+    state_home().map(|dir| dir.join("spool"))
+}
+
+impl Spool {
+    pub const fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Queued entries, oldest first.
+    pub fn entries(&self) -> Vec<PathBuf> {
+        let Ok(read_dir) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+
+        let mut entries: Vec<PathBuf> = read_dir
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+
+        entries.sort();
+        entries
+    }
+
+    /// Move an entry that won't be delivered out of the queue, into `rejected/` (where it can still be looked at).
+    fn set_aside(
+        &self,
+        path: &Path,
+    ) -> Result<PathBuf, String> {
+        let dir = self.dir.join("rejected");
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .map_err_to_string()?;
+
+        let target = dir.join(path.file_name().unwrap_or_default());
+        fs::rename(path, &target).map_err_to_string()?;
+        Ok(target)
+    }
+
+    /// Add a payload to the end of the queue.
+    pub fn push(
+        &self,
+        endpoint: &str,
+        payload: &Payload,
+        details: Option<&Details>,
+        icon: Option<&str>,
+        attachment: Option<&Attachment>,
+        sequence_id: Option<&str>,
+    ) -> Result<PathBuf, String> {
+        // queued payloads contain the command's output, so keep them private
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)
+            .map_err_to_string()?;
+
+        let now = Utc::now();
+        let entry = SpoolEntry {
+            endpoint: endpoint.to_string(),
+            payload: payload.clone(),
+            markdown: payload.markdown,
+            details: details.cloned(),
+            icon: icon.map(String::from),
+            attachment: attachment.cloned(),
+            sequence_id: sequence_id.map(String::from),
+            queued_at: now.to_rfc3339(),
+            attempts: 0,
+            next_attempt: now.timestamp(),
+        };
+
+        // zero-padded nanoseconds keep the file names sorted in queue order
+        let nanos = now.timestamp_nanos_opt().unwrap_or_default();
+        let path = self
+            .dir
+            .join(format!("{nanos:020}-{}.json", std::process::id()));
+
+        write_entry(&path, &entry)?;
+        Ok(path)
+    }
+
+    /// Deliver queued entries in order. After a failure (which is rescheduled with exponential backoff),
+    /// the remaining entries for the same endpoint are skipped to keep them in order.
+    /// Entries that are not due yet are only attempted when `force` is set.
+    /// Entries the service rejects, or that keep failing for longer than `SPOOL_MAX_AGE`, are set aside.
+    pub async fn flush(
+        &self,
+        credentials: impl Fn(&str) -> Option<Credentials>,
+        force: bool,
+    ) -> FlushResult {
+        let entries = self.entries();
+        let mut result = FlushResult {
+            remaining: entries.len(),
+            ..FlushResult::default()
+        };
+
+        if entries.is_empty() {
+            return result;
+        }
+
+        let Some(_lock) = SpoolLock::try_acquire(&self.dir) else {
+            GlobalLogger::info("Spool is being flushed by another process");
+            return result;
+        };
+
+        let mut blocked_endpoints = HashSet::new();
+
+        for path in entries {
+            let Some(mut entry) = read_entry(&path) else {
+                GlobalLogger::warn(format!(
+                    "Skipping unreadable spool entry {}",
+                    path.display()
+                ));
+                result.remaining -= 1;
+                continue;
+            };
+
+            if blocked_endpoints.contains(&entry.endpoint) {
+                continue;
+            }
+
+            let now = Utc::now().timestamp();
+            if !force && entry.next_attempt > now {
+                blocked_endpoints.insert(entry.endpoint);
+                continue;
+            }
+
+            let payload = entry.payload.clone().markdown(entry.markdown);
+            let sent = match Backend::from_endpoint(
+                &entry.endpoint,
+                credentials(&entry.endpoint).as_ref(),
+                entry.icon.clone(),
+            ) {
+                Ok(backend) => {
+                    publish_to(
+                        &backend,
+                        &payload,
+                        entry.details.as_ref(),
+                        entry.attachment.as_ref(),
+                        entry.sequence_id.as_deref(),
+                    )
+                    .await
+                },
+                Err(error) => Err(SendError::Rejected(error)),
+            };
+
+            match sent {
+                Ok(()) => {
+                    GlobalLogger::info(format!(
+                        "Delivered queued notification from {} to {}",
+                        entry.queued_at,
+                        redact_url(&entry.endpoint)
+                    ));
+                    fs::remove_file(&path).unwrap_or_default();
+                    result.delivered += 1;
+                    result.remaining -= 1;
+                },
+                Err(error) if error.is_temporary() && !entry.expired() => {
+                    entry.attempts += 1;
+                    let delay = backoff(entry.attempts);
+                    entry.next_attempt = now + delay.as_secs() as i64;
+
+                    GlobalLogger::warn(format!(
+                        "Could not deliver queued notification ({error}), next attempt in {}",
+                        humantime::format_duration(delay)
+                    ));
+                    write_entry(&path, &entry).unwrap_or_default();
+                    blocked_endpoints.insert(entry.endpoint);
+                },
+                Err(error) => {
+                    match self.set_aside(&path) {
+                        Ok(target) => GlobalLogger::warn(format!(
+                            "Giving up on queued notification from {} ({error}), moved it to {}",
+                            entry.queued_at,
+                            target.display()
+                        )),
+                        Err(move_error) => GlobalLogger::warn(format!(
+                            "Giving up on queued notification from {} ({error}), but could not move it: {move_error}",
+                            entry.queued_at
+                        )),
+                    }
+                    result.rejected += 1;
+                    result.remaining -= 1;
+                },
+            }
+        }
+
+        result
+    }
+}
+
+fn read_entry(path: &Path) -> Option<SpoolEntry> {
+    let contents = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// Write to a temporary file first, so a crash never leaves a half-written entry behind.
+fn write_entry(
+    path: &Path,
+    entry: &SpoolEntry,
+) -> Result<(), String> {
+    let contents = serde_json::to_string(entry).map_err_to_string()?;
+    let tmp_path = path.with_extension("tmp");
+
+    fs::write(&tmp_path, contents).map_err_to_string()?;
+    fs::rename(&tmp_path, path).map_err_to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use super::{backoff, read_entry, write_entry, Spool};
+    use crate::helpers::test_server;
+    use crate::ntfy::Payload;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), Duration::from_secs(30));
+        assert_eq!(backoff(1), Duration::from_mins(1));
+        assert_eq!(backoff(3), Duration::from_mins(4));
+        assert_eq!(backoff(7), Duration::from_hours(1));
+        assert_eq!(backoff(u32::MAX), Duration::from_hours(1));
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let dir = std::env::temp_dir().join(format!("ntfy-log-test-spool-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let spool = Spool::new(dir.clone());
+
+        let (up, delivered) = test_server(200).await;
+        let (down, attempted) = test_server(503).await;
+        let (invalid, rejected) = test_server(400).await;
+
+        let payload = Payload::new("jobs").message("done");
+        let old = spool.push(&down, &payload, None, None, None, None).unwrap();
+        let newer = spool.push(&down, &payload, None, None, None, None).unwrap();
+        spool
+            .push(&invalid, &payload, None, None, None, None)
+            .unwrap();
+        spool
+            .push(&up, &payload, None, None, None, Some("ntfy-log-1-2"))
+            .unwrap();
+
+        // the newer entry for `down` waits for the old one, the rejected one is set aside
+        let result = spool.flush(|_| None, false).await;
+        assert_eq!(
+            (result.delivered, result.remaining, result.rejected),
+            (1, 2, 1)
+        );
+        assert_eq!(delivered.lock().unwrap().len(), 1);
+        assert!(delivered.lock().unwrap()[0].contains("ntfy-log-1-2"));
+        assert_eq!(attempted.lock().unwrap().len(), 1);
+        assert_eq!(rejected.lock().unwrap().len(), 1);
+        assert_eq!(fs::read_dir(dir.join("rejected")).unwrap().count(), 1);
+
+        let entry = read_entry(&old).unwrap();
+        assert_eq!(entry.attempts, 1);
+        assert!(entry.next_attempt > Utc::now().timestamp());
+
+        // nothing is due yet
+        let result = spool.flush(|_| None, false).await;
+        assert_eq!((result.delivered, result.remaining), (0, 2));
+        assert_eq!(attempted.lock().unwrap().len(), 1);
+
+        // the old entry is given up on, the newer one is tried (and kept) after it
+        let mut entry = read_entry(&old).unwrap();
+        entry.queued_at = (Utc::now() - chrono::Duration::days(8)).to_rfc3339();
+        write_entry(&old, &entry).unwrap();
+
+        let result = spool.flush(|_| None, true).await;
+        assert_eq!((result.remaining, result.rejected), (1, 1));
+        assert_eq!(attempted.lock().unwrap().len(), 3);
+        assert_eq!(spool.entries(), vec![newer]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
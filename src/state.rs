@@ -0,0 +1,399 @@
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::command::CommandResult;
+use crate::config::NotifySettings;
+use crate::helpers::{state_home, ResultToString};
+use crate::log::GlobalLogger;
+
+/// When a notification is sent for a command.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotifyMode {
+    /// After every run
+    #[default]
+    Always,
+    /// Only when the command fails (or times out)
+    OnFailure,
+    /// Only when the outcome differs from the previous run
+    OnChange,
+    /// When the command fails, and once when it passes again
+    OnRecovery,
+}
+
+/// Whether (and how) to notify about a result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Notify,
+    /// The command just started flapping: notify once, after this it's muted until it settles.
+    Flapping,
+    Suppress(&'static str),
+}
+
+/// What is remembered about a command (per topic) between runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobState {
+    pub topic: String,
+    pub command: String,
+    /// Outcome of the most recent runs (`true` if it succeeded), oldest first.
+    pub history: Vec<bool>,
+    pub flapping: bool,
+    pub updated_at: String,
+
+    /// Expected time between successful runs (in seconds), see `--heartbeat`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat: Option<u64>,
+    /// Time of the last successful run with `--heartbeat`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_check_in: Option<String>,
+    /// When the heartbeat was first registered (used if there was no check-in yet).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat_since: Option<String>,
+    /// Where the job sends its notifications, so `heartbeat check` alerts there as well.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// Whether `heartbeat check` already sent an alert for the current missed check-in.
+    pub missed: bool,
+}
+
+impl JobState {
+    /// Number of times the outcome changed within the history.
+    fn transitions(&self) -> usize {
+        self.history
+            .windows(2)
+            .filter(|pair| pair[0] != pair[1])
+            .count()
+    }
+
+    /// Add an outcome (keeping the last `settings.flap_window` outcomes) and decide whether to notify about it.
+    pub fn record(
+        &mut self,
+        success: bool,
+        settings: &NotifySettings,
+    ) -> Decision {
+        let previous = self.history.last().copied();
+        let was_flapping = self.flapping;
+
+        self.history.push(success);
+        let excess = self.history.len().saturating_sub(settings.flap_window);
+        self.history.drain(..excess);
+
+        self.flapping =
+            settings.flap_threshold > 0 && self.transitions() >= settings.flap_threshold;
+        self.updated_at = now();
+
+        decide(
+            settings.mode,
+            previous,
+            success,
+            was_flapping,
+            self.flapping,
+        )
+    }
+
+    /// Remember the expected interval and the endpoint, and check in if the run succeeded.
+    pub fn record_heartbeat(
+        &mut self,
+        success: bool,
+        interval: Duration,
+        endpoint: &str,
+    ) {
+        let now = now();
+
+        self.heartbeat = Some(interval.as_secs());
+        self.endpoint = Some(endpoint.to_string());
+        self.heartbeat_since.get_or_insert_with(|| now.clone());
+
+        if success {
+            self.last_check_in = Some(now);
+            self.missed = false;
+        }
+    }
+
+    /// Time of the last check-in (or of the registration of the heartbeat), if this job has a heartbeat.
+    pub fn last_seen(&self) -> Option<DateTime<FixedOffset>> {
+        self.heartbeat?;
+
+        let last = self
+            .last_check_in
+            .as_ref()
+            .or(self.heartbeat_since.as_ref())?;
+        DateTime::parse_from_rfc3339(last).ok()
+    }
+
+    /// How long the job is overdue (beyond its interval plus `grace`), if it is.
+    pub fn overdue(
+        &self,
+        grace: Duration,
+    ) -> Option<Duration> {
+        let last_seen = self.last_seen()?;
+        let interval = Duration::from_secs(self.heartbeat?);
+
+        let elapsed = (Local::now().fixed_offset() - last_seen).to_std().ok()?;
+        elapsed
+            .checked_sub(interval + grace)
+            .filter(|overdue| !overdue.is_zero())
+    }
+}
+
+fn now() -> String {
+    Local::now().to_rfc3339_opts(SecondsFormat::Secs, false)
+}
+
+/// Without history, the command is assumed to have succeeded before (so a first failure is a change).
+const fn decide(
+    mode: NotifyMode,
+    previous: Option<bool>,
+    success: bool,
+    was_flapping: bool,
+    flapping: bool,
+) -> Decision {
+    if matches!(mode, NotifyMode::Always) {
+        return Decision::Notify;
+    }
+
+    if flapping {
+        return if was_flapping {
+            Decision::Suppress("the command is flapping")
+        } else {
+            Decision::Flapping
+        };
+    }
+
+    // a command that settled after flapping always counts as changed
+    let changed = was_flapping
+        || match previous {
+            Some(previous) => previous != success,
+            None => !success,
+        };
+
+    match mode {
+        NotifyMode::OnFailure if success => Decision::Suppress("the command succeeded"),
+        NotifyMode::OnChange | NotifyMode::OnRecovery if !changed && success => {
+            Decision::Suppress("the command still succeeds")
+        },
+        NotifyMode::OnChange if !changed => Decision::Suppress("the command still fails"),
+        _ => Decision::Notify,
+    }
+}
+
+/// FNV-1a, which (unlike `DefaultHasher`) is guaranteed to be stable between builds.
+fn job_hash(
+    topic: &str,
+    command: &str,
+) -> u64 {
+    let bytes = topic.bytes().chain([0]).chain(command.bytes());
+
+    bytes.fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// `$XDG_STATE_HOME/ntfy-log/jobs`, falling back to `~/.local/state/ntfy-log/jobs`.
+pub fn default_state_dir() -> Option<PathBuf> {
+    state_home().map(|dir| dir.join("jobs"))
+}
+
+/// Last outcomes of commands, one file per topic and command.
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    pub const fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(
+        &self,
+        topic: &str,
+        command: &str,
+    ) -> PathBuf {
+        self.dir
+            .join(format!("{:016x}.json", job_hash(topic, command)))
+    }
+
+    /// The state of a command, or an empty state if it never ran (or its state could not be read).
+    pub fn load(
+        &self,
+        topic: &str,
+        command: &str,
+    ) -> JobState {
+        fs::read_to_string(self.path(topic, command))
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_else(|| JobState {
+                topic: topic.to_string(),
+                command: command.to_string(),
+                ..JobState::default()
+            })
+    }
+
+    pub fn save(
+        &self,
+        state: &JobState,
+    ) -> Result<(), String> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)
+            .map_err_to_string()?;
+
+        write_state(&self.path(&state.topic, &state.command), state)
+    }
+
+    /// All remembered jobs, sorted by topic and command.
+    pub fn jobs(&self) -> Vec<JobState> {
+        let Ok(read_dir) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+
+        let mut jobs: Vec<JobState> = read_dir
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| {
+                let contents = fs::read_to_string(path).ok()?;
+                serde_json::from_str(&contents).ok()
+            })
+            .collect();
+
+        jobs.sort_by(|a, b| (&a.topic, &a.command).cmp(&(&b.topic, &b.command)));
+        jobs
+    }
+
+    /// Remember the outcome of `result` (and check in for `--heartbeat`, which alerts on `endpoint`),
+    /// then decide whether to notify about it (according to `settings.mode`).
+    pub fn check(
+        &self,
+        endpoint: &str,
+        topic: &str,
+        result: &CommandResult,
+        settings: &NotifySettings,
+        heartbeat: Option<Duration>,
+    ) -> Decision {
+        let mut state = self.load(topic, &result.command);
+        let decision = state.record(result.success(), settings);
+
+        if let Some(interval) = heartbeat {
+            state.record_heartbeat(result.success(), interval, endpoint);
+        }
+
+        if let Err(error) = self.save(&state) {
+            GlobalLogger::warn(format!("Could not save the state of this command: {error}"));
+        }
+
+        decision
+    }
+}
+
+/// Write to a temporary file first, so concurrent runs never read a half-written state.
+fn write_state(
+    path: &Path,
+    state: &JobState,
+) -> Result<(), String> {
+    let contents = serde_json::to_string(state).map_err_to_string()?;
+    let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+
+    fs::write(&tmp_path, contents).map_err_to_string()?;
+    fs::rename(&tmp_path, path).map_err_to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Decision, JobState, NotifyMode};
+    use crate::config::NotifySettings;
+
+    fn run(
+        mode: NotifyMode,
+        outcomes: &[bool],
+    ) -> Vec<Decision> {
+        let settings = NotifySettings {
+            mode,
+            ..NotifySettings::default()
+        };
+        let mut state = JobState::default();
+
+        outcomes
+            .iter()
+            .map(|&success| state.record(success, &settings))
+            .collect()
+    }
+
+    #[test]
+    fn test_modes() {
+        use Decision::{Notify, Suppress};
+
+        let outcomes = [true, true, false, false, true, true];
+
+        assert!(run(NotifyMode::Always, &outcomes)
+            .iter()
+            .all(|decision| *decision == Notify));
+
+        let on_failure = run(NotifyMode::OnFailure, &outcomes);
+        assert!(matches!(
+            on_failure[..],
+            [
+                Suppress(_),
+                Suppress(_),
+                Notify,
+                Notify,
+                Suppress(_),
+                Suppress(_)
+            ]
+        ));
+
+        let on_change = run(NotifyMode::OnChange, &outcomes);
+        assert!(matches!(
+            on_change[..],
+            [
+                Suppress(_),
+                Suppress(_),
+                Notify,
+                Suppress(_),
+                Notify,
+                Suppress(_)
+            ]
+        ));
+
+        let on_recovery = run(NotifyMode::OnRecovery, &outcomes);
+        assert!(matches!(
+            on_recovery[..],
+            [
+                Suppress(_),
+                Suppress(_),
+                Notify,
+                Notify,
+                Notify,
+                Suppress(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_flapping() {
+        let outcomes: Vec<bool> = (0..12).map(|run| run % 2 == 0).collect();
+        let decisions = run(NotifyMode::OnChange, &outcomes);
+
+        let notified = decisions
+            .iter()
+            .filter(|decision| !matches!(decision, Decision::Suppress(_)))
+            .count();
+
+        assert_eq!(
+            decisions
+                .iter()
+                .filter(|d| **d == Decision::Flapping)
+                .count(),
+            1
+        );
+        assert!(notified < outcomes.len() / 2);
+    }
+}
@@ -0,0 +1,212 @@
+use std::time::Duration;
+
+use atty::Stream;
+use clap::ValueEnum;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// How data piped into `ntfy-log` is turned into messages.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PipeMode {
+    /// Read until EOF and send everything as one message
+    #[default]
+    All,
+    /// Send every line as its own message
+    Line,
+    /// Send lines in batches of `--batch-lines` lines or every `--batch-interval`, whichever comes first
+    Batch,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PipeOptions {
+    pub mode: PipeMode,
+    pub batch_lines: usize,
+    pub batch_interval: Duration,
+}
+
+pub fn stdin_is_piped() -> bool {
+    !atty::is(Stream::Stdin)
+}
+
+/// Read stdin in the background and receive the messages to send according to `options.mode`.
+pub fn read_stdin(options: PipeOptions) -> mpsc::Receiver<String> {
+    read_input(tokio::io::stdin(), options)
+}
+
+fn read_input<R: AsyncRead + Unpin + Send + 'static>(
+    input: R,
+    options: PipeOptions,
+) -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel(16);
+
+    tokio::spawn(async move {
+        match options.mode {
+            PipeMode::All => read_all(input, sender).await,
+            PipeMode::Line => read_batches(input, sender, 1, None).await,
+            PipeMode::Batch => {
+                read_batches(
+                    input,
+                    sender,
+                    options.batch_lines.max(1),
+                    Some(options.batch_interval),
+                )
+                .await;
+            },
+        }
+    });
+
+    receiver
+}
+
+async fn read_all<R: AsyncRead + Unpin>(
+    mut input: R,
+    sender: mpsc::Sender<String>,
+) {
+    let mut data = Vec::new();
+    // a read error just ends the input, whatever was read so far is still sent
+    let _ = input.read_to_end(&mut data).await;
+
+    let _ = sender
+        .send(String::from_utf8_lossy(&data).into_owned())
+        .await;
+}
+
+async fn send_batch(
+    sender: &mpsc::Sender<String>,
+    batch: &mut Vec<String>,
+) -> bool {
+    let message = batch.join("\n");
+    batch.clear();
+
+    if message.trim().is_empty() {
+        return true;
+    }
+
+    sender.send(message).await.is_ok()
+}
+
+/// Collect lines until `size` lines were read or `interval` passed since the first line of the batch.
+async fn read_batches<R: AsyncRead + Unpin>(
+    input: R,
+    sender: mpsc::Sender<String>,
+    size: usize,
+    interval: Option<Duration>,
+) {
+    let mut reader = BufReader::new(input);
+    let mut batch: Vec<String> = Vec::with_capacity(size);
+    let mut deadline: Option<Instant> = None;
+    let mut line = Vec::new();
+
+    loop {
+        let flush_at = deadline;
+
+        tokio::select! {
+            read = reader.read_until(b'\n', &mut line) => {
+                if !matches!(read, Ok(size) if size > 0) {
+                    break; // EOF or error
+                }
+
+                let text = String::from_utf8_lossy(&line);
+                batch.push(text.trim_end_matches(['\n', '\r']).to_string());
+                line.clear();
+
+                if deadline.is_none() {
+                    deadline = interval.map(|interval| Instant::now() + interval);
+                }
+
+                if batch.len() >= size {
+                    deadline = None;
+                    if !send_batch(&sender, &mut batch).await {
+                        return;
+                    }
+                }
+            },
+            () = tokio::time::sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                deadline = None;
+                if !send_batch(&sender, &mut batch).await {
+                    return;
+                }
+            },
+        }
+    }
+
+    send_batch(&sender, &mut batch).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::mpsc::error::TryRecvError;
+
+    use super::{read_input, PipeMode, PipeOptions};
+
+    fn options(mode: PipeMode) -> PipeOptions {
+        PipeOptions {
+            mode,
+            batch_lines: 3,
+            batch_interval: Duration::from_secs(10),
+        }
+    }
+
+    /// All messages for `input`, which is read until EOF.
+    async fn messages(
+        mode: PipeMode,
+        input: &'static str,
+    ) -> Vec<String> {
+        let mut receiver = read_input(input.as_bytes(), options(mode));
+        let mut messages = Vec::new();
+
+        while let Some(message) = receiver.recv().await {
+            messages.push(message);
+        }
+
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_modes() {
+        let input = "one\r\ntwo\n\nthree\nfour\nfive";
+
+        assert_eq!(messages(PipeMode::All, input).await, [input]);
+        // empty lines are skipped, the last line is sent without a newline as well
+        assert_eq!(
+            messages(PipeMode::Line, input).await,
+            ["one", "two", "three", "four", "five"]
+        );
+        // every 3 lines (the empty one counts), and what's left at EOF
+        assert_eq!(
+            messages(PipeMode::Batch, input).await,
+            ["one\ntwo\n", "three\nfour\nfive"]
+        );
+
+        for mode in [PipeMode::Line, PipeMode::Batch] {
+            assert!(messages(mode, "").await.is_empty());
+            assert!(messages(mode, "\n \n").await.is_empty());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_interval() {
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let mut receiver = read_input(reader, options(PipeMode::Batch));
+
+        writer.write_all(b"one\ntwo\n").await.unwrap();
+        tokio::time::sleep(Duration::from_secs(9)).await;
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        // 10 seconds after the first line of the batch
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(receiver.try_recv().as_deref(), Ok("one\ntwo"));
+
+        // the next batch starts with its own first line
+        writer.write_all(b"three\n").await.unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        writer.write_all(b"four").await.unwrap();
+        drop(writer);
+        assert_eq!(receiver.recv().await.as_deref(), Some("three\nfour"));
+        assert_eq!(receiver.recv().await, None);
+    }
+}
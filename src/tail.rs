@@ -0,0 +1,575 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use regex::Regex;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::{TailRule, TailSettings};
+use crate::constants::TAIL_CONTEXT_WAIT;
+use crate::delivery::Delivery;
+use crate::helpers::ResultToString;
+use crate::log::GlobalLogger;
+use crate::ntfy::{Payload, Priority};
+use crate::redact::Redactor;
+
+/// A file that is followed like `tail -F`: reopened when it's rotated and read from the start when it's truncated.
+struct FollowedFile {
+    path: PathBuf,
+    file: Option<File>,
+    inode: u64,
+    offset: u64,
+    /// The last, incomplete line.
+    partial: Vec<u8>,
+    /// The last lines, for the context before a match.
+    recent: VecDeque<String>,
+}
+
+impl FollowedFile {
+    fn new(
+        path: PathBuf,
+        from_start: bool,
+    ) -> Self {
+        let mut followed = Self {
+            path,
+            file: None,
+            inode: 0,
+            offset: 0,
+            partial: Vec::new(),
+            recent: VecDeque::new(),
+        };
+
+        followed.reopen();
+
+        match &mut followed.file {
+            None => GlobalLogger::warn(format!(
+                "{} does not exist (yet), waiting for it",
+                followed.path.display()
+            )),
+            Some(file) if !from_start => {
+                followed.offset = file.seek(SeekFrom::End(0)).unwrap_or_default();
+            },
+            Some(_) => {},
+        }
+
+        followed
+    }
+
+    fn reopen(&mut self) {
+        self.file = File::open(&self.path).ok();
+        self.inode = self
+            .file
+            .as_ref()
+            .and_then(|file| file.metadata().ok())
+            .map_or(0, |metadata| metadata.ino());
+        self.offset = 0;
+    }
+
+    /// Read everything that was added to the open file, and split it into complete lines.
+    fn read_available(
+        &mut self,
+        lines: &mut Vec<String>,
+    ) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+
+        let mut buffer = Vec::new();
+        let Ok(read) = file.read_to_end(&mut buffer) else {
+            return;
+        };
+        self.offset += read as u64;
+
+        self.partial.extend_from_slice(&buffer);
+
+        if let Some(end) = self.partial.iter().rposition(|&byte| byte == b'\n') {
+            let complete: Vec<u8> = self.partial.drain(..=end).collect();
+
+            lines.extend(
+                String::from_utf8_lossy(&complete)
+                    .lines()
+                    .map(|line| line.trim_end_matches('\r').to_string()),
+            );
+        }
+    }
+
+    /// New complete lines since the last call.
+    fn read_lines(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let metadata = fs::metadata(&self.path).ok();
+
+        match (&mut self.file, metadata) {
+            (Some(_), Some(metadata)) if metadata.ino() != self.inode => {
+                // rotated: finish the old file, then continue with the new one from its start
+                self.read_available(&mut lines);
+                if !self.partial.is_empty() {
+                    lines.push(String::from_utf8_lossy(&self.partial).into_owned());
+                    self.partial.clear();
+                }
+
+                GlobalLogger::info(format!("{} was rotated, reopening it", self.path.display()));
+                self.reopen();
+            },
+            (Some(file), Some(metadata)) if metadata.len() < self.offset => {
+                GlobalLogger::info(format!(
+                    "{} was truncated, reading it from the start",
+                    self.path.display()
+                ));
+                self.offset = file.seek(SeekFrom::Start(0)).unwrap_or_default();
+                self.partial.clear();
+            },
+            (None, Some(_)) => {
+                GlobalLogger::info(format!("{} appeared, following it", self.path.display()));
+                self.reopen();
+            },
+            // removed files are read until they're replaced (by rotation)
+            _ => {},
+        }
+
+        self.read_available(&mut lines);
+        lines
+    }
+
+    fn remember(
+        &mut self,
+        line: String,
+        lines: usize,
+    ) {
+        self.recent.push_back(line);
+        while self.recent.len() > lines {
+            self.recent.pop_front();
+        }
+    }
+}
+
+/// A line that matched a rule, with its context.
+struct Match {
+    rule: usize,
+    file: usize,
+    before: Vec<String>,
+    line: String,
+    after: Vec<String>,
+    found_at: Instant,
+}
+
+impl Match {
+    /// Add a line from `file` to the context after the match, until it has `lines`.
+    fn extend(
+        &mut self,
+        file: usize,
+        line: &str,
+        lines: usize,
+    ) {
+        if self.file == file && self.after.len() < lines {
+            self.after.push(line.to_string());
+        }
+    }
+}
+
+/// A rule with its compiled pattern, and how often it was matched recently.
+struct Rule {
+    name: String,
+    regex: Regex,
+    priority: Priority,
+    tags: Vec<String>,
+    /// When the last match that was sent was found (or the last summary sent).
+    last_sent: Option<Instant>,
+    /// Matches that were not sent because of the rate limit, summarized at the end of the period.
+    suppressed: usize,
+    last_suppressed: Option<Match>,
+}
+
+impl TryFrom<&TailRule> for Rule {
+    type Error = String;
+
+    fn try_from(rule: &TailRule) -> Result<Self, Self::Error> {
+        let regex = Regex::new(&rule.pattern)
+            .map_err(|error| format!("Invalid pattern `{}`: {error}", rule.pattern))?;
+
+        Ok(Self {
+            name: rule.name.clone().unwrap_or_else(|| rule.pattern.clone()),
+            regex,
+            priority: Priority::from(rule.priority),
+            tags: rule.tags.clone(),
+            last_sent: None,
+            suppressed: 0,
+            last_suppressed: None,
+        })
+    }
+}
+
+/// Follows files and notifies about the lines that match a rule (`ntfy-log tail`).
+pub struct Tail<'a> {
+    topic: &'a str,
+    settings: &'a TailSettings,
+    delivery: &'a Delivery<'a>,
+    redactor: &'a Redactor,
+    rules: Vec<Rule>,
+    files: Vec<FollowedFile>,
+    /// Matches waiting for their context, at most one per rule and rate limit period.
+    pending: Vec<Match>,
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(
+        || path.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
+
+impl<'a> Tail<'a> {
+    /// `rules` are the rules from the command line and config, the first matching rule wins.
+    pub fn new(
+        topic: &'a str,
+        paths: &[PathBuf],
+        rules: &[TailRule],
+        settings: &'a TailSettings,
+        delivery: &'a Delivery<'a>,
+        redactor: &'a Redactor,
+        from_start: bool,
+    ) -> Result<Self, String> {
+        if rules.is_empty() {
+            return Err(String::from(
+                "Nothing to look for, add a `--match` or `[[tail.rules]]` to the config",
+            ));
+        }
+
+        let rules = rules
+            .iter()
+            .map(Rule::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let files = paths
+            .iter()
+            .map(|path| FollowedFile::new(path.clone(), from_start))
+            .collect();
+
+        Ok(Self {
+            topic,
+            settings,
+            delivery,
+            redactor,
+            rules,
+            files,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Follow the files until stopped (Ctrl+C, `SIGTERM` or `SIGHUP`), then send what's still pending.
+    pub async fn run(&mut self) -> Result<(), String> {
+        let mut interval = tokio::time::interval(self.settings.poll_interval);
+        let mut interrupt = signal(SignalKind::interrupt()).map_err_to_string()?;
+        let mut terminate = signal(SignalKind::terminate()).map_err_to_string()?;
+        let mut hangup = signal(SignalKind::hangup()).map_err_to_string()?;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = interrupt.recv() => break,
+                _ = terminate.recv() => break,
+                _ = hangup.recv() => break,
+            }
+
+            self.poll();
+            self.send_matches(false).await;
+            self.send_summaries(false).await;
+        }
+
+        self.poll();
+        self.send_matches(true).await;
+        self.send_summaries(true).await;
+        Ok(())
+    }
+
+    /// Read new lines from all files and collect the ones that match. A match of a rule that is rate limited
+    /// is only counted (and kept as the last one, for the summary).
+    fn poll(&mut self) {
+        let after = self.settings.after;
+
+        for (index, file) in self.files.iter_mut().enumerate() {
+            for line in file.read_lines() {
+                for pending in &mut self.pending {
+                    pending.extend(index, &line, after);
+                }
+                for rule in &mut self.rules {
+                    if let Some(found) = &mut rule.last_suppressed {
+                        found.extend(index, &line, after);
+                    }
+                }
+
+                if let Some(position) = self
+                    .rules
+                    .iter()
+                    .position(|rule| rule.regex.is_match(&line))
+                {
+                    let found = Match {
+                        rule: position,
+                        file: index,
+                        before: file.recent.iter().cloned().collect(),
+                        line: line.clone(),
+                        after: Vec::new(),
+                        found_at: Instant::now(),
+                    };
+
+                    let rule = &mut self.rules[position];
+                    if rule
+                        .last_sent
+                        .is_some_and(|sent| sent.elapsed() < self.settings.rate_limit)
+                    {
+                        rule.suppressed += 1;
+                        rule.last_suppressed = Some(found);
+                    } else {
+                        rule.last_sent = Some(found.found_at);
+                        self.pending.push(found);
+                    }
+                }
+
+                file.remember(line, self.settings.before);
+            }
+        }
+    }
+
+    fn payload(
+        &self,
+        title: String,
+        found: &Match,
+        intro: Option<String>,
+    ) -> Payload {
+        let rule = &self.rules[found.rule];
+        let mut message = intro
+            .map(|intro| format!("{intro}\n\n"))
+            .unwrap_or_default();
+
+        for line in &found.before {
+            let _ = writeln!(message, "  {line}");
+        }
+        let _ = writeln!(message, "> {}", found.line);
+        for line in &found.after {
+            let _ = writeln!(message, "  {line}");
+        }
+
+        let payload = Payload::new(self.topic)
+            .title(title)
+            .message(self.redactor.redact(message.trim_end()))
+            .priority(rule.priority.clone());
+
+        if rule.tags.is_empty() {
+            payload
+        } else {
+            payload.tags(rule.tags.clone())
+        }
+    }
+
+    async fn send(
+        &self,
+        payload: &Payload,
+    ) {
+        if let Err(error) = self.delivery.send(payload).await {
+            GlobalLogger::error(format!("Could not send or queue notification: {error}"));
+        }
+    }
+
+    /// Send matches with all their context (or that waited long enough for it).
+    async fn send_matches(
+        &mut self,
+        all: bool,
+    ) {
+        let after = self.settings.after;
+        let (ready, waiting): (Vec<Match>, Vec<Match>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|found| {
+                all || found.after.len() >= after || found.found_at.elapsed() >= TAIL_CONTEXT_WAIT
+            });
+        self.pending = waiting;
+
+        for found in ready {
+            let path = &self.files[found.file].path;
+            let title = format!("{} in {}", self.rules[found.rule].name, file_name(path));
+            let payload = self.payload(title, &found, None);
+            self.send(&payload).await;
+        }
+    }
+
+    /// One notification per rate limited rule, with the number of matches that were not sent and the last one.
+    async fn send_summaries(
+        &mut self,
+        all: bool,
+    ) {
+        for index in 0..self.rules.len() {
+            let rule = &mut self.rules[index];
+
+            let period_over = rule
+                .last_sent
+                .is_none_or(|sent| sent.elapsed() >= self.settings.rate_limit);
+
+            if rule.suppressed == 0 || !(all || period_over) {
+                continue;
+            }
+
+            let Some(found) = rule.last_suppressed.take() else {
+                continue;
+            };
+
+            let count = std::mem::take(&mut rule.suppressed);
+            rule.last_sent = Some(Instant::now());
+
+            let path = &self.files[found.file].path;
+            let title = format!("{}: {count} more in {}", rule.name, file_name(path));
+            let intro = format!(
+                "{count} more line(s) matched within {}, the last one:",
+                humantime::format_duration(self.settings.rate_limit)
+            );
+
+            let payload = self.payload(title, &found, Some(intro));
+            self.send(&payload).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use super::{FollowedFile, Tail};
+    use crate::config::{RedactSettings, Settings, TailRule, TailSettings};
+    use crate::delivery::Delivery;
+    use crate::helpers::test_server;
+    use crate::ntfy::PriorityLevel;
+    use crate::redact::Redactor;
+
+    fn append(
+        path: &std::path::Path,
+        text: &str,
+    ) {
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let path =
+            std::env::temp_dir().join(format!("ntfy-log-test-rotation-{}.log", std::process::id()));
+        let rotated = path.with_extension("log.1");
+        fs::write(&path, "before\n").unwrap();
+
+        let mut followed = FollowedFile::new(path.clone(), false);
+        append(&path, "one\ntw");
+        assert_eq!(followed.read_lines(), ["one"]);
+
+        // what was still written to the old file is read before the new one
+        append(&path, "o\nthree");
+        fs::rename(&path, &rotated).unwrap();
+        fs::write(&path, "new one\n").unwrap();
+        assert_eq!(followed.read_lines(), ["two", "three", "new one"]);
+
+        append(&path, "new two\n");
+        append(&rotated, "too late\n");
+        assert_eq!(followed.read_lines(), ["new two"]);
+        assert!(followed.read_lines().is_empty());
+
+        // removed, and created again
+        fs::remove_file(&path).unwrap();
+        assert!(followed.read_lines().is_empty());
+        fs::write(&path, "recreated\n").unwrap();
+        assert_eq!(followed.read_lines(), ["recreated"]);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+
+    #[test]
+    fn test_truncation() {
+        let path = std::env::temp_dir().join(format!(
+            "ntfy-log-test-truncation-{}.log",
+            std::process::id()
+        ));
+        fs::write(&path, "one\ntwo\n").unwrap();
+
+        let mut followed = FollowedFile::new(path.clone(), true);
+        assert_eq!(followed.read_lines(), ["one", "two"]);
+
+        // shorter than what was read: read again from the start
+        fs::write(&path, "three\n").unwrap();
+        assert_eq!(followed.read_lines(), ["three"]);
+
+        append(&path, "four\n");
+        assert_eq!(followed.read_lines(), ["four"]);
+
+        fs::File::create(&path).unwrap();
+        assert!(followed.read_lines().is_empty());
+        append(&path, "five\n");
+        assert_eq!(followed.read_lines(), ["five"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let path =
+            std::env::temp_dir().join(format!("ntfy-log-test-tail-{}.log", std::process::id()));
+        fs::write(&path, "starting\n").unwrap();
+
+        let (endpoint, requests) = test_server(200).await;
+        let mut settings = Settings::default();
+        settings.spool.enabled = false;
+        let delivery = Delivery::to_endpoint(&settings, &endpoint).unwrap();
+        let redactor = Redactor::new(&RedactSettings::default()).unwrap();
+        let tail_settings = TailSettings {
+            before: 1,
+            after: 1,
+            ..TailSettings::default()
+        };
+        let rules = [TailRule {
+            pattern: String::from("ERROR"),
+            name: Some(String::from("Errors")),
+            priority: PriorityLevel::High,
+            tags: Vec::new(),
+        }];
+
+        let mut tail = Tail::new(
+            "jobs",
+            std::slice::from_ref(&path),
+            &rules,
+            &tail_settings,
+            &delivery,
+            &redactor,
+            true,
+        )
+        .unwrap();
+
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        for index in 0..1000 {
+            writeln!(file, "ERROR {index}\nok {index}").unwrap();
+        }
+
+        // only the first match waits to be sent, the others are counted
+        tail.poll();
+        assert_eq!(tail.pending.len(), 1);
+        assert_eq!(tail.pending[0].before, ["starting"]);
+        assert_eq!(tail.pending[0].after, ["ok 0"]);
+        assert_eq!(tail.rules[0].suppressed, 999);
+        let last = tail.rules[0].last_suppressed.as_ref().unwrap();
+        assert_eq!(last.line, "ERROR 999");
+        assert_eq!(last.after, ["ok 999"]);
+
+        tail.send_matches(false).await;
+        tail.send_summaries(false).await;
+        assert!(tail.pending.is_empty());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        tail.send_summaries(true).await;
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        assert!(requests.lock().unwrap()[1].contains("Errors: 999 more in ntfy-log-test-tail"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+
+use crate::config::Settings;
+use crate::delivery::Delivery;
+use crate::helpers::redact_url;
+use crate::notifier::is_ntfy;
+
+/// A topic on an ntfy server (or another service) that notifications are sent to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub endpoint: String,
+    pub topic: String,
+}
+
+impl fmt::Display for Target {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        let endpoint = redact_url(&self.endpoint);
+
+        if is_ntfy(&self.endpoint) {
+            write!(f, "{}/{}", endpoint.trim_end_matches('/'), self.topic)
+        } else {
+            write!(f, "{endpoint}")
+        }
+    }
+}
+
+/// A target with the delivery to its endpoint.
+pub struct Destination<'a> {
+    pub target: &'a Target,
+    pub delivery: Delivery<'a>,
+}
+
+impl<'a> Destination<'a> {
+    /// Fails if any of the endpoints is invalid.
+    pub fn all(
+        targets: &'a [Target],
+        settings: &'a Settings,
+    ) -> Result<Vec<Self>, String> {
+        targets
+            .iter()
+            .map(|target| {
+                Ok(Self {
+                    target,
+                    delivery: Delivery::to_endpoint(settings, &target.endpoint)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// One `--to` target: `server/topic` (where `server` may be one of `[servers]`), just a topic on `endpoint`,
+/// or the endpoint of another service (which gets `topic`).
+fn parse_target(
+    value: &str,
+    topic: &str,
+    settings: &Settings,
+) -> Result<Target, String> {
+    if !is_ntfy(value) {
+        return Ok(Target {
+            endpoint: value.to_string(),
+            topic: topic.to_string(),
+        });
+    }
+
+    let (scheme, rest) = value.split_once("://").unwrap_or(("", value));
+
+    let Some((server, target_topic)) = rest.rsplit_once('/') else {
+        if scheme.is_empty() && !rest.is_empty() {
+            return Ok(Target {
+                endpoint: settings.endpoint.clone(),
+                topic: rest.to_string(),
+            });
+        }
+
+        return Err(format!("Invalid target '{value}': expected `server/topic`"));
+    };
+
+    if server.is_empty() || target_topic.is_empty() {
+        return Err(format!("Invalid target '{value}': expected `server/topic`"));
+    }
+
+    let endpoint = match settings.servers.get(server) {
+        Some(named) if scheme.is_empty() => named.endpoint.clone(),
+        _ if scheme.is_empty() => server.to_string(),
+        _ => format!("{scheme}://{server}"),
+    };
+
+    Ok(Target {
+        endpoint,
+        topic: target_topic.to_string(),
+    })
+}
+
+/// The targets for `--to` (each may also be a group from `[groups]`), or `topic` on `endpoint` without any.
+pub fn resolve_targets(
+    to: &[String],
+    topic: &str,
+    settings: &Settings,
+) -> Result<Vec<Target>, String> {
+    if to.is_empty() {
+        return Ok(vec![Target {
+            endpoint: settings.endpoint.clone(),
+            topic: topic.to_string(),
+        }]);
+    }
+
+    let mut targets = Vec::new();
+
+    for value in to {
+        let members = settings
+            .groups
+            .get(value)
+            .map_or_else(|| std::slice::from_ref(value), Vec::as_slice);
+
+        for member in members {
+            let target = parse_target(member, topic, settings)?;
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+    }
+
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_targets, Target};
+    use crate::config::Settings;
+
+    #[test]
+    fn test_resolve_targets() {
+        let settings: Settings = toml::from_str(
+            "endpoint = 'ntfy.example.com'\n\
+             [groups]\noncall = ['ntfy.sh/ops', 'selfhosted/ops-archive', 'ops']\n\
+             [servers.selfhosted]\nendpoint = 'http://ntfy.lan:8080'\ntoken = 'tk_abc'",
+        )
+        .unwrap();
+
+        let target = |endpoint: &str, topic: &str| Target {
+            endpoint: endpoint.to_string(),
+            topic: topic.to_string(),
+        };
+
+        let resolve = |to: &[&str]| {
+            let to: Vec<String> = to.iter().map(ToString::to_string).collect();
+            resolve_targets(&to, "backup", &settings)
+        };
+
+        assert_eq!(
+            resolve(&[]).unwrap(),
+            [target("ntfy.example.com", "backup")]
+        );
+        assert_eq!(
+            resolve(&["oncall", "ntfy.sh/ops", "https://ntfy.other.com/x"]).unwrap(),
+            [
+                target("ntfy.sh", "ops"),
+                target("http://ntfy.lan:8080", "ops-archive"),
+                target("ntfy.example.com", "ops"),
+                target("https://ntfy.other.com", "x"),
+            ]
+        );
+        assert_eq!(
+            resolve(&["slack://hooks.slack.com/services/a/b"]).unwrap(),
+            [target("slack://hooks.slack.com/services/a/b", "backup")]
+        );
+
+        assert_eq!(
+            resolve(&["selfhosted/ops"]).unwrap()[0].to_string(),
+            "http://ntfy.lan:8080/ops"
+        );
+        assert_eq!(
+            settings
+                .credentials_for("http://ntfy.lan:8080")
+                .map(|credentials| credentials.to_auth().as_base64()),
+            settings.servers["selfhosted"]
+                .credentials()
+                .map(|credentials| credentials.to_auth().as_base64())
+        );
+        assert!(settings.credentials_for("ntfy.sh").is_none());
+
+        assert!(resolve(&["https://ntfy.sh"]).is_err());
+        assert!(resolve(&["ntfy.sh/"]).is_err());
+    }
+}
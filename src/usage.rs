@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+
+/// Resources used by the wrapped command (and everything it started), based on `getrusage(2)`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub user_cpu_ms: u64,
+    pub system_cpu_ms: u64,
+    /// Peak resident set size in kilobytes.
+    pub max_rss_kb: u64,
+    /// Number of block input operations (reads that had to go to disk).
+    pub block_reads: u64,
+    /// Number of block output operations.
+    pub block_writes: u64,
+}
+
+#[allow(clippy::unnecessary_cast)] // `time_t`/`suseconds_t` are not `i64` on every target
+fn timeval_ms(time: libc::timeval) -> u64 {
+    let millis = (time.tv_sec as i64) * 1000 + (time.tv_usec as i64) / 1000;
+    u64::try_from(millis).unwrap_or_default()
+}
+
+fn non_negative(value: libc::c_long) -> u64 {
+    u64::try_from(value).unwrap_or_default()
+}
+
+fn children_rusage() -> Option<libc::rusage> {
+    // SAFETY: `rusage` is plain old data and fully initialized by `getrusage` on success.
+    unsafe {
+        let mut usage: libc::rusage = std::mem::zeroed();
+        if libc::getrusage(libc::RUSAGE_CHILDREN, &raw mut usage) != 0 {
+            return None;
+        }
+        Some(usage)
+    }
+}
+
+impl ResourceUsage {
+    /// Totals of all children of this process that have been waited for.
+    pub fn children() -> Option<Self> {
+        let usage = children_rusage()?;
+
+        Some(Self {
+            user_cpu_ms: timeval_ms(usage.ru_utime),
+            system_cpu_ms: timeval_ms(usage.ru_stime),
+            max_rss_kb: non_negative(usage.ru_maxrss),
+            block_reads: non_negative(usage.ru_inblock),
+            block_writes: non_negative(usage.ru_oublock),
+        })
+    }
+
+    /// Usage between an earlier snapshot (`before`) and this one.
+    /// Peak RSS can't be subtracted, so the peak of all waited-for children is kept.
+    pub const fn since(
+        &self,
+        before: &Self,
+    ) -> Self {
+        Self {
+            user_cpu_ms: self.user_cpu_ms.saturating_sub(before.user_cpu_ms),
+            system_cpu_ms: self.system_cpu_ms.saturating_sub(before.system_cpu_ms),
+            max_rss_kb: self.max_rss_kb,
+            block_reads: self.block_reads.saturating_sub(before.block_reads),
+            block_writes: self.block_writes.saturating_sub(before.block_writes),
+        }
+    }
+}